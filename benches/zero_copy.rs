@@ -0,0 +1,112 @@
+//Compares sending a file over loopback with sendfile against copying it through a buffer, the
+//two ways send_file can go. Run with cargo bench; without --bench it only checks both work.
+extern crate send;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileExt;
+
+const BENCH_SIZE: u64 = 1024 * 1024 * 1024;
+const CHECK_SIZE: u64 = 8 * 1024 * 1024;
+const RUNS: u32 = 3;
+//The same pieces the sending side uses
+const COPY_BUFFER: usize = 64 * 1024;
+const SENDFILE_CHUNK: usize = 4 * 1024 * 1024;
+
+fn make_file(size: u64) -> (std::path::PathBuf, std::fs::File) {
+    let path = std::env::temp_dir().join(format!("send-bench-{}", std::process::id()));
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    let mut block = vec![0u8; COPY_BUFFER];
+    let mut state = 0x2545F4914F6CDD1Du64;
+    let mut written = 0;
+    while written < size {
+        for byte in block.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+        let len = std::cmp::min(block.len() as u64, size - written) as usize;
+        file.write_all(&block[..len]).unwrap();
+        written += len as u64;
+    }
+    file.sync_all().unwrap();
+    return (path, file);
+}
+
+//Connects a socket to a thread that reads and throws away everything sent to it
+fn sink() -> (TcpStream, std::thread::JoinHandle<u64>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let reader = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = vec![0u8; COPY_BUFFER];
+        let mut total = 0;
+        loop {
+            let read = stream.read(&mut buffer).unwrap();
+            if read == 0 {
+                return total;
+            }
+            total += read as u64;
+        }
+    });
+    return (TcpStream::connect(addr).unwrap(), reader);
+}
+
+fn send_copy(stream: &mut TcpStream, file: &std::fs::File, size: u64) {
+    let mut buffer = vec![0u8; COPY_BUFFER];
+    let mut offset = 0;
+    while offset < size {
+        let len = std::cmp::min(buffer.len() as u64, size - offset) as usize;
+        let read = file.read_at(&mut buffer[..len], offset).unwrap();
+        stream.write_all(&buffer[..read]).unwrap();
+        offset += read as u64;
+    }
+}
+
+fn send_zero_copy(stream: &mut TcpStream, file: &std::fs::File, size: u64) {
+    let mut offset = 0;
+    while offset < size {
+        let len = std::cmp::min(SENDFILE_CHUNK as u64, size - offset) as usize;
+        offset += send::network::sendfile(stream, file, offset, len).unwrap() as u64;
+    }
+}
+
+//Best throughput of the runs in MB/s
+fn measure(file: &std::fs::File, size: u64, runs: u32, send: fn(&mut TcpStream, &std::fs::File, u64)) -> f64 {
+    let mut best = 0.0f64;
+    for _ in 0..runs {
+        let (mut stream, reader) = sink();
+        let start = std::time::Instant::now();
+        send(&mut stream, file, size);
+        drop(stream);
+        assert_eq!(reader.join().unwrap(), size);
+        let elapsed = start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        best = best.max(size as f64 / secs / 1e6);
+    }
+    return best;
+}
+
+fn main() {
+    let bench = std::env::args().any(|x| x == "--bench");
+    let (size, runs) = if bench { (BENCH_SIZE, RUNS) } else { (CHECK_SIZE, 1) };
+    let (path, file) = make_file(size);
+
+    //Warm the page cache, so both read the file from memory
+    measure(&file, size, 1, send_copy);
+    let copy = measure(&file, size, runs, send_copy);
+    let zero_copy = measure(&file, size, runs, send_zero_copy);
+    std::fs::remove_file(&path).unwrap();
+
+    println!("Sent {} bytes over loopback, best of {}", size, runs);
+    println!("copy      {:>10.1} MB/s", copy);
+    println!("sendfile  {:>10.1} MB/s", zero_copy);
+    println!("ratio     {:>10.2}x", zero_copy / copy);
+}
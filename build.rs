@@ -1,5 +1,5 @@
 extern crate byteorder;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, WriteBytesExt};
 
 use std::env;
 use std::fs::File;
@@ -8,9 +8,8 @@ use std::path::Path;
 use std::io::BufReader;
 use std::io::BufRead;
 
-//@Performance: Pretty slow
-//@Memory: Probably a big waste
-//@Hack: This should really be in some other datastructure so i wouldn't have to read all of it
+const WORD_FILE: &'static str = "./newwords.txt";
+
 fn read_into_vector<P: AsRef<Path>>(path: P) -> Vec<String> {
     let in_file = File::open(path).unwrap();
     let in_buf = BufReader::new(in_file);
@@ -22,17 +21,64 @@ fn read_into_vector<P: AsRef<Path>>(path: P) -> Vec<String> {
     return lines;
 }
 
+//Keys are lowercased and split on punctuation before the words are looked up, so a word with
+//anything but lowercase letters could never be decoded.
+fn valid_char(c: char) -> bool {
+    return c.is_ascii_lowercase();
+}
+
+fn validate(words: &[String]) {
+    if words.is_empty() {
+        panic!("{} contains no words", WORD_FILE);
+    }
+    for (i, word) in words.iter().enumerate() {
+        let line = i + 1;
+        if word.is_empty() {
+            panic!("{}:{}: Empty word", WORD_FILE, line);
+        }
+        if let Some(c) = word.chars().find(|c| !valid_char(*c)) {
+            panic!("{}:{}: Word {:?} contains invalid character {:?}", WORD_FILE, line, word, c);
+        }
+        if i > 0 {
+            //The presenter does a binary search on the bytes, so the ordering has to match
+            //the byte ordering exactly
+            let prev = &words[i - 1];
+            if prev == word {
+                panic!("{}:{}: Duplicate word {:?}", WORD_FILE, line, word);
+            }
+            if prev.as_bytes() > word.as_bytes() {
+                panic!("{}:{}: Word {:?} is not sorted (comes after {:?})", WORD_FILE, line, word, prev);
+            }
+        }
+    }
+}
+
 fn main() {
+    println!("cargo:rerun-if-changed={}", WORD_FILE);
+    println!("cargo:rerun-if-changed=build.rs");
+
     let out_dir = env::var("OUT_DIR").unwrap();
-    let dest_path = Path::new(&out_dir).join("words.rs");
-    let mut f = File::create(&dest_path).unwrap();
-    let words = read_into_vector("./newwords.txt");
+    let words = read_into_vector(WORD_FILE);
+    validate(&words);
 
-    f.write_all(b"
-    fn make_list() -> (Box<[&'static str]>, u32) {
-        return (Box::new([");
+    //The dictionary is stored as two blobs. One with all the words glued together, and one with
+    //the big endian u32 offset of each word into the first, with a final entry marking the end.
+    let mut data = File::create(Path::new(&out_dir).join("words.bin")).unwrap();
+    let mut offsets = File::create(Path::new(&out_dir).join("offsets.bin")).unwrap();
+    let mut offset: u32 = 0;
     for word in &words {
-        write!(f, "\"{}\",\n", word).unwrap();
+        offsets.write_u32::<BigEndian>(offset).unwrap();
+        data.write_all(word.as_bytes()).unwrap();
+        offset += word.len() as u32;
     }
-    write!(f, "]), {});}}", words.len());
+    offsets.write_u32::<BigEndian>(offset).unwrap();
+
+    let mut f = File::create(Path::new(&out_dir).join("words.rs")).unwrap();
+    write!(f, "
+    static WORD_DATA: &'static [u8] = include_bytes!(concat!(env!(\"OUT_DIR\"), \"/words.bin\"));
+    static WORD_OFFSETS: &'static [u8] = include_bytes!(concat!(env!(\"OUT_DIR\"), \"/offsets.bin\"));
+
+    fn make_list() -> send::dictionary::Dictionary<'static> {{
+        return send::dictionary::Dictionary::new(WORD_DATA, WORD_OFFSETS, {});
+    }}", words.len()).unwrap();
 }
@@ -0,0 +1,792 @@
+use std::io;
+use std::io::{Read, Write};
+use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use ignore::WalkBuilder;
+use ignore::gitignore::GitignoreBuilder;
+use tar;
+use super::errors::*;
+use super::is_set;
+use super::disk;
+
+//Directories go over the wire as tar archives. The archive is made while it is sent and unpacked
+//while it comes in, so it never has to fit on either disk as a whole.
+
+//What to do with symbolic links
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Links {
+    //Send what they point to, as if it was there instead
+    Follow,
+    //Send them as links, to be made again on the other side
+    Preserve,
+    //Leave them out
+    Skip,
+}
+
+impl Links {
+    pub fn parse(s: &str) -> Result<Links> {
+        return match s {
+            "follow" => Ok(Links::Follow),
+            "preserve" => Ok(Links::Preserve),
+            "skip" => Ok(Links::Skip),
+            _ => bail!(ErrorKind::InvalidLinks(s.to_owned())),
+        };
+    }
+}
+
+//What something is, and what else it takes to make it again
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Dir,
+    File,
+    //A symbolic link and where it points
+    Symlink(PathBuf),
+    //Another name for a file that is earlier in the archive
+    HardLink(PathBuf),
+    //A device or FIFO
+    Special,
+}
+
+//Looks at what is at path, going through it if it's a link we are told to follow. None is for
+//links we are told to skip. Devices and FIFOs are only let through with special, and sockets
+//never are
+pub fn inspect(path: &Path, links: Links, special: bool) -> Result<Option<(fs::Metadata, Kind)>> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        match links {
+            Links::Follow => {},
+            Links::Preserve => return Ok(Some((metadata, Kind::Symlink(fs::read_link(path)?)))),
+            Links::Skip => return Ok(None),
+        }
+    }
+    let metadata = fs::metadata(path)?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        Kind::Dir
+    } else if file_type.is_file() {
+        Kind::File
+    } else if file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device() {
+        if !special {
+            bail!(ErrorKind::SpecialFile(path.to_owned()));
+        }
+        Kind::Special
+    } else {
+        bail!(ErrorKind::UnsupportedFile(path.to_owned()));
+    };
+    return Ok(Some((metadata, kind)));
+}
+
+//Something in the directory being sent
+pub struct Entry {
+    //Where it is on disk
+    pub path: PathBuf,
+    //Where it goes in the archive, relative to the directory
+    pub name: PathBuf,
+    pub metadata: fs::Metadata,
+    pub kind: Kind,
+}
+
+//Something that had to be left out. The reason names it
+pub struct Skipped {
+    pub name: PathBuf,
+    pub reason: String,
+}
+
+//Decides what is left out of a directory and what happens to links. Excludes are written like
+//lines of a .gitignore, and match relative to the directory
+#[derive(Clone)]
+pub struct Filter {
+    excludes: GitignoreBuilder,
+    //Honor the .gitignore and .ignore files in the directory
+    ignore_files: bool,
+    links: Links,
+    //Send devices and FIFOs instead of leaving them out
+    special: bool,
+}
+
+impl Filter {
+    pub fn new(root: &Path) -> Self {
+        return Filter {
+            excludes: GitignoreBuilder::new(root),
+            ignore_files: false,
+            links: Links::Follow,
+            special: false,
+        };
+    }
+
+    pub fn add_exclude(&mut self, pattern: &str) -> Result<()> {
+        self.excludes.add_line(None, pattern)
+            .chain_err(|| ErrorKind::InvalidExclude(pattern.to_owned()))?;
+        return Ok(());
+    }
+
+    //Every line of the file is an exclude, with # starting comments
+    pub fn add_exclude_from(&mut self, path: &Path) -> Result<()> {
+        if let Some(err) = self.excludes.add(path) {
+            return Err(err).chain_err(|| ErrorKind::ExcludeFile(path.to_owned()));
+        }
+        return Ok(());
+    }
+
+    pub fn set_ignore_files(&mut self, ignore_files: bool) {
+        self.ignore_files = ignore_files;
+    }
+
+    pub fn set_links(&mut self, links: Links) {
+        self.links = links;
+    }
+
+    pub fn set_special(&mut self, special: bool) {
+        self.special = special;
+    }
+}
+
+//Everything in the directory the filter lets through, each directory before what's in it, and
+//what had to be left out. Names are sorted, so the same directory makes the same archive
+pub fn walk(root: &Path, filter: &Filter) -> Result<(Vec<Entry>, Vec<Skipped>)> {
+    let excludes = filter.excludes.build()?;
+    let ignore_files = filter.ignore_files;
+    let mut walker = WalkBuilder::new(root);
+    walker.standard_filters(false)
+        .git_ignore(ignore_files)
+        .ignore(ignore_files)
+        //They count even when the directory isn't in a repository
+        .require_git(false)
+        .follow_links(filter.links == Links::Follow)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(move |x| {
+            let is_dir = x.file_type().map_or(false, |x| x.is_dir());
+            //Git never looks at what's in .git, so going by what it ignores leaves that out too
+            if ignore_files && is_dir && x.file_name() == ".git" {
+                return false;
+            }
+            return !excludes.matched(x.path(), is_dir).is_ignore();
+        });
+
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    //The first name each file with more than one has, by device and inode
+    let mut hard_links = HashMap::new();
+    for entry in walker.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => match broken_link(&err) {
+                Some((path, reason)) => {
+                    skipped.push(Skipped {
+                        name: path.strip_prefix(root).unwrap_or(path).to_owned(),
+                        reason: format!("{} {}", path.display(), reason),
+                    });
+                    continue;
+                }
+                None => return Err(err.into()),
+            },
+        };
+        if entry.depth() == 0 {
+            continue;
+        }
+        let path = entry.path().to_owned();
+        let name = match path.strip_prefix(root) {
+            Ok(name) => name.to_owned(),
+            Err(_) => bail!(ErrorKind::PathConversion),
+        };
+        let (metadata, kind) = match inspect(&path, filter.links, filter.special) {
+            Ok(Some(found)) => found,
+            Ok(None) => continue,
+            Err(err) => match *err.kind() {
+                ErrorKind::SpecialFile(_) | ErrorKind::UnsupportedFile(_) => {
+                    skipped.push(Skipped {
+                        name: name,
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+                _ => return Err(err),
+            },
+        };
+        //Followed links are sent as copies of what they point to, only real names are hard links
+        let kind = if kind == Kind::File && metadata.nlink() > 1 && !entry.path_is_symlink() {
+            let first = hard_links.entry((metadata.dev(), metadata.ino())).or_insert_with(|| name.clone());
+            if *first == name {
+                Kind::File
+            } else {
+                Kind::HardLink(first.clone())
+            }
+        } else {
+            kind
+        };
+        entries.push(Entry {
+            path: path,
+            name: name,
+            metadata: metadata,
+            kind: kind,
+        });
+    }
+    return Ok((entries, skipped));
+}
+
+//Following links can lead back to where we came from, or to nothing at all. Those are left out
+//instead of failing the whole walk
+fn broken_link(err: &::ignore::Error) -> Option<(&Path, &'static str)> {
+    return match *err {
+        ::ignore::Error::Loop { ref child, .. } => Some((child, "links to a directory it is in")),
+        ::ignore::Error::WithPath { ref path, ref err } => {
+            let is_link = fs::symlink_metadata(path).map_or(false, |x| x.file_type().is_symlink());
+            let not_found = err.io_error().map_or(false, |x| x.kind() == io::ErrorKind::NotFound);
+            if is_link && not_found {
+                Some((path, "links to something that isn't there"))
+            } else {
+                broken_link(err)
+            }
+        }
+        ::ignore::Error::WithDepth { ref err, .. } |
+        ::ignore::Error::WithLineNumber { ref err, .. } => broken_link(err),
+        _ => None,
+    };
+}
+
+//Writes the entries out as a tar archive and gives back the writer. Gives up with
+//TransferAborted once abort is set
+pub fn write<W: Write>(entries: &[Entry], out: W, abort: Option<&AtomicBool>) -> Result<W> {
+    let mut builder = tar::Builder::new(out);
+    for entry in entries {
+        if is_set(abort) {
+            bail!(ErrorKind::TransferAborted);
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&entry.metadata);
+        let res = match entry.kind {
+            Kind::Dir => builder.append_data(&mut header, &entry.name, io::empty()),
+            Kind::File => {
+                let file = fs::File::open(&entry.path)?;
+                let content = EntryReader {
+                    path: &entry.path,
+                    content: file.take(entry.metadata.len()),
+                    left: entry.metadata.len(),
+                    abort: abort,
+                };
+                builder.append_data(&mut header, &entry.name, content)
+            }
+            Kind::Symlink(ref target) => {
+                header.set_size(0);
+                builder.append_link(&mut header, &entry.name, target)
+            }
+            Kind::HardLink(ref first) => {
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_size(0);
+                builder.append_link(&mut header, &entry.name, first)
+            }
+            Kind::Special => {
+                let (major, minor) = disk::device_numbers(entry.metadata.rdev());
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+                header.set_size(0);
+                builder.append_data(&mut header, &entry.name, io::empty())
+            }
+        };
+        if let Err(err) = res {
+            if is_set(abort) {
+                bail!(ErrorKind::TransferAborted);
+            }
+            return Err(err.into());
+        }
+    }
+    return Ok(builder.into_inner()?);
+}
+
+//The header already has the size in it, so a file that shrinks while we read it has to fail the
+//archive instead of leaving the rest of it out of place
+struct EntryReader<'a> {
+    path: &'a Path,
+    content: io::Take<fs::File>,
+    left: u64,
+    abort: Option<&'a AtomicBool>,
+}
+
+impl<'a> Read for EntryReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if is_set(self.abort) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Aborted"));
+        }
+        let read = self.content.read(buf)?;
+        if read == 0 && self.left > 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("{} got shorter while sending it", self.path.display())));
+        }
+        self.left -= read as u64;
+        return Ok(read);
+    }
+}
+
+//What ended up on disk. Files are everything that isn't a directory, but only regular files
+//have bytes
+pub struct Unpacked {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+//Unpacks an archive into dir, which has to exist already. Everything has to stay inside dir,
+//links included. Devices and FIFOs are only made with special. Whatever comes after the end of
+//the archive is read too, since the end of the stream might have something to say
+pub fn unpack<R: Read>(input: R, dir: &Path, special: bool) -> Result<Unpacked> {
+    let mut archive = tar::Archive::new(input);
+    archive.set_overwrite(false);
+    let unpacked = unpack_entries(&mut archive, dir, special)?;
+
+    //The end of an archive is all zeros, so anything else is something we weren't meant to see
+    let mut rest = archive.into_inner();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = rest.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(unpacked);
+        }
+        if buffer[..read].iter().any(|x| *x != 0) {
+            bail!(ErrorKind::TrailingData);
+        }
+    }
+}
+
+fn unpack_entries<R: Read>(archive: &mut tar::Archive<R>, dir: &Path, special: bool) -> Result<Unpacked> {
+    let mut unpacked = Unpacked {
+        files: 0,
+        bytes: 0,
+    };
+    //Directories are made as soon as something in them needs them, but only get their own
+    //permissions once everything is in. A read only directory would keep its entries out.
+    let mut directories = Vec::new();
+    let mut seen = HashSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !is_safe(&path) {
+            bail!(ErrorKind::UnsafePath(path));
+        }
+        //We never send anything twice, and a second one would land on top of the first
+        if !seen.insert(path.components().filter(|x| *x != Component::CurDir).collect::<PathBuf>()) {
+            bail!(ErrorKind::DuplicateEntry(path));
+        }
+        let entry_type = entry.header().entry_type();
+        let target = entry.link_name()?.map(|x| x.into_owned());
+        match (entry_type, target) {
+            (tar::EntryType::Regular, _) => {},
+            (tar::EntryType::Directory, _) => {
+                directories.push(entry);
+                continue;
+            }
+            //Hard links name something earlier in the archive
+            (tar::EntryType::Link, Some(ref target)) => {
+                if !is_safe(target) {
+                    bail!(ErrorKind::UnsafeLink(path, target.clone()));
+                }
+            }
+            (tar::EntryType::Symlink, Some(ref target)) => {
+                if !link_stays_inside(dir, &path, target)? {
+                    bail!(ErrorKind::UnsafeLink(path, target.clone()));
+                }
+            }
+            (tar::EntryType::Fifo, _) | (tar::EntryType::Char, _) | (tar::EntryType::Block, _) if special => {
+                unpack_special(&entry, dir, &path)?;
+                unpacked.files += 1;
+                continue;
+            }
+            _ => bail!(ErrorKind::UnsupportedEntry(path)),
+        }
+        //Also checks that no directory on the way there leads outside
+        if !entry.unpack_in(dir)? {
+            bail!(ErrorKind::UnsafePath(path));
+        }
+        unpacked.files += 1;
+        if entry_type == tar::EntryType::Regular {
+            unpacked.bytes += entry.header().size()?;
+        }
+    }
+
+    //Children before their parents, so none of them are locked out by the time they are done
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut entry in directories {
+        if !entry.unpack_in(dir)? {
+            bail!(ErrorKind::UnsafePath(entry.path()?.into_owned()));
+        }
+    }
+    return Ok(unpacked);
+}
+
+//Removes what unpack left, even the directories it made read only
+pub fn remove(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fn make_writable(dir: &Path) -> io::Result<()> {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                make_writable(&entry.path())?;
+            }
+        }
+        return Ok(());
+    }
+
+    //Whatever can't be made writable might still go
+    let _ = make_writable(dir);
+    return fs::remove_dir_all(dir);
+}
+
+//tar would make devices and FIFOs plain files, so they are made here
+fn unpack_special<R: Read>(entry: &tar::Entry<R>, dir: &Path, path: &Path) -> Result<()> {
+    let header = entry.header();
+    let special = match header.entry_type() {
+        tar::EntryType::Fifo => disk::Special::Fifo,
+        tar::EntryType::Char => disk::Special::Char,
+        tar::EntryType::Block => disk::Special::Block,
+        _ => bail!(ErrorKind::UnsupportedEntry(path.to_owned())),
+    };
+    let (parent, _) = real_parent(dir, path)?;
+    let major = header.device_major()?.unwrap_or(0);
+    let minor = header.device_minor()?.unwrap_or(0);
+    disk::make_special(&parent.join(path.file_name().unwrap()), special, header.mode()?, major, minor)?;
+    return Ok(());
+}
+
+//Relative and never going up
+fn is_safe(path: &Path) -> bool {
+    let mut components = path.components().peekable();
+    if components.peek().is_none() {
+        return false;
+    }
+    return components.all(|x| match x {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+}
+
+//Where the directory an entry goes in really is, and how deep inside dir that is. Links that
+//came earlier might have put it somewhere else than its name says. Directory entries are only
+//unpacked at the end, so the directories on the way are made here, like tar does for files
+fn real_parent(dir: &Path, path: &Path) -> Result<(PathBuf, usize)> {
+    let dir = dir.canonicalize()?;
+    let parent = match dir.join(path).parent() {
+        Some(parent) => {
+            fs::create_dir_all(parent)?;
+            parent.canonicalize()?
+        }
+        None => bail!(ErrorKind::UnsafePath(path.to_owned())),
+    };
+    return match parent.strip_prefix(&dir) {
+        Ok(inside) => Ok((parent.clone(), inside.components().count())),
+        Err(_) => bail!(ErrorKind::UnsafePath(path.to_owned())),
+    };
+}
+
+//Links can only go up from where they really are, then down. Going up after going down could go
+//through another link to anywhere
+fn link_stays_inside(dir: &Path, path: &Path, target: &Path) -> Result<bool> {
+    let (_, mut depth) = real_parent(dir, path)?;
+    let mut down = false;
+    for component in target.components() {
+        match component {
+            Component::CurDir => {},
+            Component::Normal(_) => down = true,
+            Component::ParentDir if !down && depth > 0 => depth -= 1,
+            _ => return Ok(false),
+        }
+    }
+    return Ok(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A directory of its own for each test, with the unpacking done in out inside it. Anything
+    //but out showing up means something got outside.
+    struct Scratch {
+        base: PathBuf,
+        out: PathBuf,
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let base = std::env::temp_dir().join(format!("send-archive-{}-{}", std::process::id(), name));
+            let _ = remove(&base);
+            let out = base.join("out");
+            fs::create_dir_all(&out).unwrap();
+            return Scratch {
+                base: base,
+                out: out,
+            };
+        }
+
+        fn outside(&self) -> Vec<PathBuf> {
+            return fs::read_dir(&self.base).unwrap()
+                .map(|x| x.unwrap().path())
+                .filter(|x| *x != self.out)
+                .collect();
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = remove(&self.base);
+        }
+    }
+
+    //Archives are made by hand, since tar's builder refuses to write the paths we want to see
+    //refused
+    struct Archive(tar::Builder<Vec<u8>>);
+
+    impl Archive {
+        fn new() -> Self {
+            return Archive(tar::Builder::new(Vec::new()));
+        }
+
+        fn add(mut self, path: &str, entry_type: tar::EntryType, mode: u32, target: Option<&str>, data: &[u8]) -> Self {
+            let mut header = tar::Header::new_gnu();
+            {
+                let name = &mut header.as_old_mut().name;
+                name[..path.len()].copy_from_slice(path.as_bytes());
+            }
+            if let Some(target) = target {
+                let link = &mut header.as_old_mut().linkname;
+                link[..target.len()].copy_from_slice(target.as_bytes());
+            }
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            self.0.append(&header, data).unwrap();
+            return self;
+        }
+
+        fn file(self, path: &str, data: &[u8]) -> Self {
+            return self.add(path, tar::EntryType::Regular, 0o644, None, data);
+        }
+
+        fn dir(self, path: &str, mode: u32) -> Self {
+            return self.add(path, tar::EntryType::Directory, mode, None, &[]);
+        }
+
+        fn symlink(self, path: &str, target: &str) -> Self {
+            return self.add(path, tar::EntryType::Symlink, 0o777, Some(target), &[]);
+        }
+
+        fn hard_link(self, path: &str, target: &str) -> Self {
+            return self.add(path, tar::EntryType::Link, 0o644, Some(target), &[]);
+        }
+
+        fn finish(self) -> Vec<u8> {
+            return self.0.into_inner().unwrap();
+        }
+    }
+
+    fn unpack_into(scratch: &Scratch, archive: &[u8]) -> Result<Unpacked> {
+        return unpack(archive, &scratch.out, false);
+    }
+
+    #[test]
+    fn unpacks_files_and_directories() {
+        let scratch = Scratch::new("plain");
+        let archive = Archive::new()
+            .dir("a", 0o755)
+            .file("a/one", b"one")
+            .dir("a/b", 0o755)
+            .file("a/b/two", b"two!")
+            .finish();
+        let unpacked = unpack_into(&scratch, &archive).unwrap();
+        assert_eq!(unpacked.files, 2);
+        assert_eq!(unpacked.bytes, 7);
+        assert_eq!(fs::read(scratch.out.join("a/b/two")).unwrap(), b"two!");
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn read_only_directories_get_their_files_first() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let scratch = Scratch::new("readonly");
+        let archive = Archive::new()
+            .dir("ro", 0o555)
+            .file("ro/inside", b"data")
+            .dir("ro/deeper", 0o555)
+            .file("ro/deeper/more", b"more")
+            .finish();
+        unpack_into(&scratch, &archive).unwrap();
+        assert_eq!(fs::read(scratch.out.join("ro/deeper/more")).unwrap(), b"more");
+        let mode = fs::metadata(scratch.out.join("ro")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o555);
+
+        remove(&scratch.out).unwrap();
+        assert!(!scratch.out.exists());
+    }
+
+    #[test]
+    fn refuses_going_up() {
+        let scratch = Scratch::new("up");
+        let archive = Archive::new().file("../x", b"escaped").finish();
+        match unpack_into(&scratch, &archive) {
+            Err(Error(ErrorKind::UnsafePath(ref path), _)) => assert_eq!(path, Path::new("../x")),
+            res => panic!("Unpacking ../x gave {:?}", res.map(|x| x.files)),
+        }
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn refuses_absolute_paths() {
+        let scratch = Scratch::new("abs");
+        let target = scratch.base.join("abs");
+        let archive = Archive::new().file(target.to_str().unwrap(), b"escaped").finish();
+        match unpack_into(&scratch, &archive) {
+            Err(Error(ErrorKind::UnsafePath(_), _)) => {},
+            res => panic!("Unpacking an absolute path gave {:?}", res.map(|x| x.files)),
+        }
+        assert!(!target.exists());
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn refuses_going_up_halfway() {
+        let scratch = Scratch::new("halfway");
+        let archive = Archive::new()
+            .dir("a", 0o755)
+            .file("a/../../x", b"escaped")
+            .finish();
+        match unpack_into(&scratch, &archive) {
+            Err(Error(ErrorKind::UnsafePath(_), _)) => {},
+            res => panic!("Unpacking a/../../x gave {:?}", res.map(|x| x.files)),
+        }
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn refuses_duplicates() {
+        let scratch = Scratch::new("duplicate");
+        let archive = Archive::new()
+            .file("dup", b"first")
+            .file("./dup", b"second")
+            .finish();
+        match unpack_into(&scratch, &archive) {
+            Err(Error(ErrorKind::DuplicateEntry(_), _)) => {},
+            res => panic!("Unpacking a duplicate gave {:?}", res.map(|x| x.files)),
+        }
+        assert_eq!(fs::read(scratch.out.join("dup")).unwrap(), b"first");
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn refuses_data_after_the_end() {
+        let scratch = Scratch::new("trailing");
+        let mut archive = Archive::new().file("fine", b"fine").finish();
+        archive.extend_from_slice(&Archive::new().file("hidden", b"hidden").finish());
+        match unpack_into(&scratch, &archive) {
+            Err(Error(ErrorKind::TrailingData, _)) => {},
+            res => panic!("Unpacking with trailing data gave {:?}", res.map(|x| x.files)),
+        }
+        assert!(!scratch.out.join("hidden").exists());
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn allows_padding_after_the_end() {
+        let scratch = Scratch::new("padding");
+        let mut archive = Archive::new().file("fine", b"fine").finish();
+        archive.extend_from_slice(&[0u8; 10240]);
+        unpack_into(&scratch, &archive).unwrap();
+    }
+
+    fn assert_unsafe_link(res: Result<Unpacked>, expected: &str) {
+        match res {
+            Err(Error(ErrorKind::UnsafeLink(ref path, _), _)) => assert_eq!(path, Path::new(expected)),
+            res => panic!("Unpacking {} gave {:?}", expected, res.map(|x| x.files)),
+        }
+    }
+
+    #[test]
+    fn real_parent_follows_links() {
+        use std::os::unix::fs::symlink;
+
+        let scratch = Scratch::new("parent");
+        fs::create_dir_all(scratch.out.join("a/b")).unwrap();
+        symlink("a/b", scratch.out.join("l")).unwrap();
+        let out = scratch.out.canonicalize().unwrap();
+        assert_eq!(real_parent(&scratch.out, Path::new("top")).unwrap(), (out.clone(), 0));
+        assert_eq!(real_parent(&scratch.out, Path::new("a/b/c")).unwrap(), (out.join("a/b"), 2));
+        assert_eq!(real_parent(&scratch.out, Path::new("l/c")).unwrap(), (out.join("a/b"), 2));
+        //Missing directories are made on the way
+        assert_eq!(real_parent(&scratch.out, Path::new("new/c")).unwrap(), (out.join("new"), 1));
+    }
+
+    #[test]
+    fn links_stay_inside() {
+        let scratch = Scratch::new("inside");
+        let out = &scratch.out;
+        assert!(link_stays_inside(out, Path::new("x/l"), Path::new("..")).unwrap());
+        assert!(link_stays_inside(out, Path::new("x/y/l"), Path::new("../../z")).unwrap());
+        assert!(link_stays_inside(out, Path::new("x/l"), Path::new("./../z/w")).unwrap());
+        assert!(!link_stays_inside(out, Path::new("l"), Path::new("..")).unwrap());
+        assert!(!link_stays_inside(out, Path::new("x/l"), Path::new("../..")).unwrap());
+        assert!(!link_stays_inside(out, Path::new("x/l"), Path::new("z/../..")).unwrap());
+        assert!(!link_stays_inside(out, Path::new("x/l"), Path::new("/etc")).unwrap());
+    }
+
+    #[test]
+    fn refuses_links_up_from_the_top() {
+        let scratch = Scratch::new("linkup");
+        let archive = Archive::new().symlink("l", "..").finish();
+        assert_unsafe_link(unpack_into(&scratch, &archive), "l");
+        assert!(fs::symlink_metadata(scratch.out.join("l")).is_err());
+    }
+
+    #[test]
+    fn refuses_links_up_through_links() {
+        let scratch = Scratch::new("linkthrough");
+        //x/l is fine on its own, but it really is the top, so going up again from it isn't
+        let archive = Archive::new()
+            .dir("x", 0o755)
+            .symlink("x/l", "..")
+            .symlink("x/l/m", "..")
+            .file("x/l/m/escaped", b"escaped")
+            .finish();
+        assert_unsafe_link(unpack_into(&scratch, &archive), "x/l/m");
+        assert!(fs::symlink_metadata(scratch.out.join("m")).is_err());
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn refuses_absolute_links() {
+        let scratch = Scratch::new("linkabs");
+        let archive = Archive::new()
+            .symlink("l", scratch.base.to_str().unwrap())
+            .file("l/escaped", b"escaped")
+            .finish();
+        assert_unsafe_link(unpack_into(&scratch, &archive), "l");
+        assert!(scratch.outside().is_empty());
+    }
+
+    #[test]
+    fn refuses_hard_links_going_up() {
+        let scratch = Scratch::new("hardup");
+        fs::write(scratch.base.join("x"), b"outside").unwrap();
+        let archive = Archive::new().hard_link("h", "../x").finish();
+        assert_unsafe_link(unpack_into(&scratch, &archive), "h");
+        assert!(!scratch.out.join("h").exists());
+    }
+
+    #[test]
+    fn makes_links_that_stay_inside() {
+        let scratch = Scratch::new("links");
+        let archive = Archive::new()
+            .dir("x", 0o755)
+            .file("x/f", b"content")
+            .symlink("x/up", "..")
+            .symlink("y/l", "../x/f")
+            .hard_link("h", "x/f")
+            .finish();
+        let unpacked = unpack_into(&scratch, &archive).unwrap();
+        assert_eq!(unpacked.files, 4);
+        assert_eq!(fs::read(scratch.out.join("y/l")).unwrap(), b"content");
+        assert_eq!(fs::read(scratch.out.join("x/up/h")).unwrap(), b"content");
+        assert!(scratch.outside().is_empty());
+    }
+}
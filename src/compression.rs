@@ -0,0 +1,167 @@
+use std;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use flate2;
+use zstd;
+
+//What the content can be compressed with. The id is what goes on the wire
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Zstd,
+    Deflate,
+}
+
+//Best first
+const PREFERRED: [Codec; 2] = [Codec::Zstd, Codec::Deflate];
+
+impl Codec {
+    pub fn id(&self) -> u8 {
+        return match *self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Deflate => 2,
+        };
+    }
+
+    pub fn from_id(id: u8) -> Option<Codec> {
+        return match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Deflate),
+            _ => None,
+        };
+    }
+
+    //Everything we can decompress, as a bitmask of ids
+    pub fn supported() -> u8 {
+        return PREFERRED.iter().fold(0, |mask, x| mask | 1 << x.id());
+    }
+
+    //The best codec the other side says it can handle
+    pub fn negotiate(mask: u8) -> Codec {
+        return PREFERRED.iter()
+            .cloned()
+            .find(|x| mask & 1 << x.id() != 0)
+            .unwrap_or(Codec::None);
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "{}", match *self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Deflate => "deflate",
+        });
+    }
+}
+
+//How much of the content to look at before deciding
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+//Compressed data looks random, and random data has close to 8 bits of entropy per byte
+const MAX_ENTROPY: f64 = 7.5;
+
+//Guesses from a sample if the content is worth compressing, so we don't waste time on things
+//that are compressed already
+pub fn is_compressible(sample: &[u8]) -> bool {
+    if sample.is_empty() {
+        return false;
+    }
+    let mut counts = [0u32; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy = counts.iter()
+        .filter(|x| **x > 0)
+        .map(|x| {
+            let p = *x as f64 / len;
+            return -p * p.log2();
+        })
+        .sum::<f64>();
+    return entropy < MAX_ENTROPY;
+}
+
+pub enum Encoder<W: Write> {
+    Plain(W),
+    Zstd(zstd::Encoder<'static, W>),
+    Deflate(flate2::write::DeflateEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(codec: Codec, inner: W) -> io::Result<Self> {
+        return Ok(match codec {
+            Codec::None => Encoder::Plain(inner),
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(inner, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+            //Deflate is only there for when zstd isn't, so it might as well be quick
+            Codec::Deflate => Encoder::Deflate(flate2::write::DeflateEncoder::new(inner, flate2::Compression::fast())),
+        });
+    }
+
+    //Writes out whatever is still held back and gives back the inner writer
+    pub fn finish(self) -> io::Result<W> {
+        return match self {
+            Encoder::Plain(inner) => Ok(inner),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        };
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return match *self {
+            Encoder::Plain(ref mut inner) => inner.write(buf),
+            Encoder::Zstd(ref mut encoder) => encoder.write(buf),
+            Encoder::Deflate(ref mut encoder) => encoder.write(buf),
+        };
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return match *self {
+            Encoder::Plain(ref mut inner) => inner.flush(),
+            Encoder::Zstd(ref mut encoder) => encoder.flush(),
+            Encoder::Deflate(ref mut encoder) => encoder.flush(),
+        };
+    }
+}
+
+pub enum Decoder<R: Read> {
+    Plain(R),
+    Zstd(zstd::Decoder<'static, BufReader<R>>),
+    Deflate(flate2::bufread::DeflateDecoder<BufReader<R>>),
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(codec: Codec, inner: R) -> io::Result<Self> {
+        return Ok(match codec {
+            Codec::None => Decoder::Plain(inner),
+            Codec::Zstd => Decoder::Zstd(zstd::Decoder::new(inner)?),
+            Codec::Deflate => Decoder::Deflate(flate2::bufread::DeflateDecoder::new(BufReader::new(inner))),
+        });
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match *self {
+            Decoder::Plain(ref mut inner) => return inner.read(buf),
+            Decoder::Zstd(ref mut decoder) => decoder.read(buf)?,
+            Decoder::Deflate(ref mut decoder) => decoder.read(buf)?,
+        };
+        //The compressed content can end before the stream it came in does. The stream still has
+        //to be read to its end, since that might have something to say, like a checksum
+        if read == 0 && !buf.is_empty() {
+            let inner = match *self {
+                Decoder::Plain(ref mut inner) => return inner.read(buf),
+                Decoder::Zstd(ref mut decoder) => decoder.get_mut(),
+                Decoder::Deflate(ref mut decoder) => decoder.get_mut(),
+            };
+            if inner.read(&mut [0u8; 1])? != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Got more after the end of the compressed content"));
+            }
+        }
+        return Ok(read);
+    }
+}
@@ -0,0 +1,79 @@
+use std;
+use std::cmp::Ordering;
+use std::ops::Range;
+use byteorder::{BigEndian, ByteOrder};
+
+//A sorted list of words stored without any allocation. All words are glued together in one blob,
+//and the offsets blob holds the big endian u32 start of each word followed by the end of the last
+//word. This is the format build.rs generates.
+#[derive(Clone, Copy)]
+pub struct Dictionary<'a> {
+    data: &'a [u8],
+    offsets: &'a [u8],
+    len: u32,
+}
+
+impl<'a> Dictionary<'a> {
+    pub fn new(data: &'a [u8], offsets: &'a [u8], len: u32) -> Self {
+        assert_eq!(offsets.len(), (len as usize + 1) * 4, "Dictionary offset table doesn't match the word count");
+        return Dictionary {
+            data: data,
+            offsets: offsets,
+            len: len,
+        };
+    }
+
+    pub fn len(&self) -> u32 {
+        return self.len;
+    }
+
+    fn offset(&self, index: u32) -> usize {
+        let start = index as usize * 4;
+        return BigEndian::read_u32(&self.offsets[start..start + 4]) as usize;
+    }
+
+    pub fn get(&self, index: u32) -> &'a str {
+        assert!(index < self.len, "Dictionary index out of bounds");
+        let word = &self.data[self.offset(index)..self.offset(index + 1)];
+        //build.rs only lets ascii through
+        return std::str::from_utf8(word).unwrap();
+    }
+
+    pub fn find(&self, word: &str) -> Option<u32> {
+        let mut low = 0;
+        let mut high = self.len;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.get(mid).as_bytes().cmp(word.as_bytes()) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Some(mid),
+            }
+        }
+        return None;
+    }
+
+    //Words are sorted, so all words starting with the prefix are next to each other
+    pub fn prefix_range(&self, prefix: &str) -> Range<u32> {
+        let prefix = prefix.as_bytes();
+        let start = self.partition(|word| word < prefix);
+        let end = self.partition(|word| word < prefix || word.starts_with(prefix));
+        return start..end;
+    }
+
+    //Index of the first word the predicate is false for. The predicate has to be true for a
+    //leading part of the dictionary and false for the rest
+    fn partition<F: Fn(&[u8]) -> bool>(&self, pred: F) -> u32 {
+        let mut low = 0;
+        let mut high = self.len;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.get(mid).as_bytes()) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        return low;
+    }
+}
@@ -0,0 +1,64 @@
+extern crate libc;
+
+use std::io;
+use std::ffi::CString;
+use std::fs::File;
+use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+
+//Reserve room for the whole file up front, so running out of space shows up before the transfer
+//instead of halfway through, and the file doesn't end up scattered all over the disk. The size
+//of the file is left alone, so an unfinished file still looks unfinished.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let ret = unsafe{ libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+//@Expansion: posix_fallocate would work most places, but it also sets the size
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+}
+
+//Preallocating is only worth failing over if it tells us the file won't fit
+pub fn is_out_of_space(err: &io::Error) -> bool {
+    return err.raw_os_error() == Some(libc::ENOSPC);
+}
+
+//Splits a device number from metadata into the major and minor numbers archives carry
+pub fn device_numbers(rdev: u64) -> (u32, u32) {
+    return (libc::major(rdev as libc::dev_t) as u32, libc::minor(rdev as libc::dev_t) as u32);
+}
+
+//The special files that can be made again somewhere else
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Special {
+    Fifo,
+    Char,
+    Block,
+}
+
+//Recreates a device or FIFO. Devices usually take root
+pub fn make_special(path: &Path, special: Special, permissions: u32, major: u32, minor: u32) -> io::Result<()> {
+    let file_type = match special {
+        Special::Fifo => libc::S_IFIFO,
+        Special::Char => libc::S_IFCHR,
+        Special::Block => libc::S_IFBLK,
+    };
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mode = file_type | (permissions & 0o7777) as libc::mode_t;
+    let ret = unsafe{ libc::mknod(path.as_ptr(), mode, libc::makedev(major, minor)) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
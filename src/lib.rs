@@ -13,20 +13,36 @@ extern crate clap;
 extern crate byteorder;
 extern crate ansi_term;
 extern crate pbr;
+extern crate qrcode;
+extern crate crc32fast;
+extern crate flate2;
+extern crate zstd;
+extern crate tar;
+extern crate ignore;
 
 pub mod network;
+pub mod disk;
+pub mod compression;
+pub mod archive;
+pub mod limit;
+pub mod dictionary;
+pub mod relay;
 
 use std::path::PathBuf;
-use std::io::{Read, Write};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Seek, Write};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use ansi_term::Colour::*;
-use std::cmp::Ordering;
 use pbr::{ProgressBar, Units};
+use dictionary::Dictionary;
+use compression::Codec;
+use limit::{Limits, Rate, RateLimit, Throttled};
+use qrcode::{QrCode, Color};
 
 pub mod errors {
     use std::io;
     use std::net;
     use std::path;
+    use super::Checksum;
     error_chain! {
         // The type defined for this error. These are the conventional
         // and recommended names, but they can be arbitrarily chosen.
@@ -66,6 +82,7 @@ pub mod errors {
         // This section can be empty.
         foreign_links {
             Io(io::Error) #[cfg(unix)];
+            Ignore(::ignore::Error);
         }
 
         // Define additional `ErrorKind` variants. The syntax here is
@@ -85,6 +102,66 @@ pub mod errors {
                 description("Error while sending file")
                 display("While sending to {}", remote_addr)
             }
+            ReceiveFile(remote_addr: net::SocketAddr){
+                description("Error while receiving file")
+                display("While receiving from {}", remote_addr)
+            }
+            UploadTooLarge(name: String, size: u64, max: u64) {
+                description("Upload larger than allowed")
+                display("Upload of {} is {} bytes, but only {} are allowed", name, size, max)
+            }
+            QuotaExceeded(name: String, size: u64, left: u64) {
+                description("Upload doesn't fit in the quota")
+                display("Upload of {} is {} bytes, but only {} are left in the quota", name, size, left)
+            }
+            InboxUsage(dir: ::std::path::PathBuf) {
+                description("Couldn't count the files already in the inbox")
+                display("Couldn't count the files already in {}", dir.to_string_lossy())
+            }
+            UploadDenied(name: String) {
+                description("Upload was denied")
+                display("Upload of {} was denied", name)
+            }
+            InvalidNumber(number: String) {
+                description("Number not valid")
+                display("Invalid number: {}", number)
+            }
+            InvalidSize(size: String) {
+                description("Size not valid")
+                display("Invalid size: {}", size)
+            }
+            InvalidRate(rate: String) {
+                description("Rate not valid")
+                display("Invalid rate: {}. Leave the limit out or turn it off to not limit", rate)
+            }
+            InvalidDuration(duration: String) {
+                description("Duration not valid")
+                display("Invalid duration: {}", duration)
+            }
+            InvalidRelayCode {
+                description("Relay code not valid")
+                display("Got an invalid relay code")
+            }
+            RelayPairing(code: String) {
+                description("Relay failed to pair us")
+                display("While waiting at the relay for a peer with code {}", code)
+            }
+            RelayCodeTaken(code: String) {
+                description("Relay code already in use")
+                display("Someone else is already waiting at the relay with code {}", code)
+            }
+            NoRelayCode {
+                description("Key has no relay code")
+                display("The key has no relay code. Use the key the server gave for the relay")
+            }
+            RelayConnection(remote_addr: net::SocketAddr) {
+                description("Error while relaying connection")
+                display("While relaying for {}", remote_addr)
+            }
+            ConnectAttempt(attempt: u32, attempts: u32) {
+                description("Connection attempt failed")
+                display("Connection attempt {} of {} failed", attempt, attempts)
+            }
             UnknownFile(index: u32) {
                 description("The client requested an unknown file")
                 display("The client requested an unknown file with id {}", index)
@@ -117,6 +194,34 @@ pub mod errors {
                 description("Transport not valid")
                 display("Invalid transport: {}", t)
             }
+            InvalidAddress(addr: String) {
+                description("Address not valid")
+                display("Invalid address: {}", addr)
+            }
+            ResolveHost(host: String) {
+                description("Failed resolving host")
+                display("Failed to find an IPv4 address for {}", host)
+            }
+            UnknownWord(word: String, index: usize, column: usize) {
+                description("Key contains a word that isn't in the dictionary")
+                display("Word {} of the key, \"{}\" at column {}, is not in the dictionary", index, word, column)
+            }
+            NoMatchingInterface(host: u32) {
+                description("No local interface has room for the host part")
+                display("No local interface has a subnet that fits host {}", host)
+            }
+            QrEncode(data: String) {
+                description("Failed encoding QR code")
+                display("Failed encoding {} as a QR code", data)
+            }
+            InvalidAddressList {
+                description("Key doesn't hold a valid list of addresses")
+                display("Key is too long for a single address, but isn't a valid list of addresses either")
+            }
+            TransportSize(bits: u32) {
+                description("Transport payload too large")
+                display("The key holds more than the expected {} bits", bits)
+            }
             FileExists(p: ::std::path::PathBuf) {
                 description("File already exists")
                 display("Tried to write to existing file: {}", p.to_string_lossy())
@@ -129,11 +234,129 @@ pub mod errors {
                 description("An error occured while reading content from network")
                 display("While reading content from network")
             }
+            Remote(code: u16, message: String) {
+                description("The other side reported an error")
+                display("The other side failed with code {}: {}", code, message)
+            }
+            Unavailable {
+                description("Nothing left to download")
+                display("The server isn't handing out any more downloads")
+            }
+            ChecksumMismatch {
+                description("Checksum of the content didn't match")
+                display("The content doesn't match the checksum the sender computed")
+            }
+            Receipt {
+                description("Failed getting the receipt")
+                display("While waiting for the receiver to confirm the file")
+            }
+            NotDelivered(bytes: u64, checksum: Checksum) {
+                description("The receiver failed storing the file")
+                display("The receiver failed after writing {} bytes ({})", bytes, checksum)
+            }
+            InvalidFrame {
+                description("Got a frame of unknown type")
+                display("Got a frame of unknown type, the other side might be a different version")
+            }
+            TransferAborted {
+                description("Transfer was aborted")
+                display("The transfer was aborted before it was done")
+            }
+            InvalidRange(range: String) {
+                description("Range not valid")
+                display("Invalid range: {}, expected START-END, START- or -COUNT", range)
+            }
+            RangeNotSatisfiable(start: u64, total: u64) {
+                description("Range starts past the end of the file")
+                display("The range starts at {}, but the file is only {} bytes", start, total)
+            }
+            InvalidPart(part: u16, parts: u16) {
+                description("Asked for a part the file isn't split into")
+                display("Asked for part {} of a file split into {} parts", part, parts)
+            }
+            TransferDone(transfer: u64) {
+                description("Transfer is already done")
+                display("Transfer {:x} is already done", transfer)
+            }
+            TransferMismatch(transfer: u64) {
+                description("Part doesn't match the rest of the transfer")
+                display("Asked for a different split or range than the rest of transfer {:x}", transfer)
+            }
+            NoRanges {
+                description("Asked for a range of a directory or link")
+                display("Directories and links are sent whole, there are no ranges of them")
+            }
+            DirectoryUpload(p: ::std::path::PathBuf) {
+                description("Tried to upload a directory")
+                display("{} is a directory, only files can be uploaded", p.to_string_lossy())
+            }
+            UnsafeName(name: String) {
+                description("Sender named the file with a path")
+                display("The other side named the file {:?}, which isn't a plain file name", name)
+            }
+            UnsafePath(p: ::std::path::PathBuf) {
+                description("Archive has a path that leads outside of it")
+                display("The archive has {}, which would end up outside of where it is unpacked", p.to_string_lossy())
+            }
+            InvalidExclude(pattern: String) {
+                description("Exclude pattern not valid")
+                display("Invalid exclude pattern: {}", pattern)
+            }
+            ExcludeFile(p: ::std::path::PathBuf) {
+                description("Failed reading excludes from a file")
+                display("While reading excludes from {}", p.to_string_lossy())
+            }
+            UnsupportedEntry(p: ::std::path::PathBuf) {
+                description("Archive has something that can't be unpacked")
+                display("The archive has {}, which isn't a file, directory or link, or wasn't asked for", p.to_string_lossy())
+            }
+            LinkToStdout(name: String) {
+                description("A link can't be written to stdout")
+                display("{} is a link, which can only be fetched to a file", name)
+            }
+            DuplicateEntry(p: ::std::path::PathBuf) {
+                description("Archive has the same path twice")
+                display("The archive has {} more than once", p.to_string_lossy())
+            }
+            TrailingData {
+                description("Archive has data after its end")
+                display("The archive has something after its end")
+            }
+            UnsafeLink(p: ::std::path::PathBuf, target: ::std::path::PathBuf) {
+                description("Archive has a link that leads outside of it")
+                display("The archive has {} linking to {}, which is outside of where it is unpacked", p.to_string_lossy(), target.to_string_lossy())
+            }
+            InvalidLinks(links: String) {
+                description("Way of handling links not valid")
+                display("Invalid way of handling links: {}, expected follow, preserve or skip", links)
+            }
+            SkippedLink(p: ::std::path::PathBuf) {
+                description("Path is a link, and links are skipped")
+                display("{} is a link, and links are being skipped", p.to_string_lossy())
+            }
+            SpecialFile(p: ::std::path::PathBuf) {
+                description("Path is a device or FIFO, and those weren't asked for")
+                display("{} is a device or FIFO, which are only sent in a directory and when asked for", p.to_string_lossy())
+            }
+            UnsupportedFile(p: ::std::path::PathBuf) {
+                description("Path is something that can't be sent")
+                display("{} isn't a file, directory, link, device or FIFO", p.to_string_lossy())
+            }
         }
     }
 }
 use errors::*;
 
+//Errors go to stderr, so they don't end up in whatever stdout is piped into
+pub fn print_err<T: std::fmt::Display + std::error::Error>(err: T) {
+    eprintln!(" {} {}", Red.paint("==>"), err);
+    let mut terr : &std::error::Error = &err;
+    while let Some(serr) = terr.cause() {
+        eprintln!("    {} {}", Yellow.paint("==>"), serr);
+        terr = serr;
+    }
+}
+
 trait Readn {
     fn readn(&mut self, buff: &mut Vec<u8>, n: usize) -> std::io::Result<usize>;
 }
@@ -146,315 +369,3268 @@ impl<T: Read> Readn for T {
 }
 
 trait Streamable<'a>{
-    fn read<T: Read + 'a>(stream: T) -> Result<Self> where Self: std::marker::Sized;
+    fn read<T: Read + Send + 'a>(stream: T) -> Result<Self> where Self: std::marker::Sized;
     fn write<T: Write + 'a>(&mut self, stream: &mut T) -> Result<usize>;
 }
 
-struct FileMessage<'a> {
-    name_size: u32,
-    name: String,
-    size: u32,
-    file: Box<Read + 'a>,
-}
-
-impl<'a> FileMessage<'a> {
-    fn new<T: Read + 'a>(name: String, size: u32, stream: T) -> Self {
-        return FileMessage {
-            name_size:  name.len() as u32, //@Expansion: 32 bits is a lot, but maybe in the far flung future.
-            name: name,
-            size: size,
-            file: Box::new(stream)
-        };
-    }
-}
+//Everything is sent as frames, so the sender can tell us when something went wrong, even halfway
+//through the file. Every frame starts with its type.
+//File frames start a message with the name and size of the file, and data frames carry a big
+//endian u32 length and a piece of the content. The content ends with an end frame holding the
+//crc32 of all of it, or an unchecked end frame if the sender never had the content in hand.
+const FRAME_FILE: u8 = 0;
+const FRAME_DATA: u8 = 1;
+//Error frames have a big endian u16 code, a u32 length and a utf8 message
+const FRAME_ERROR: u8 = 2;
+const FRAME_END: u8 = 3;
+//Goes the other way, from the receiver once the file is stored. See Receipt
+const FRAME_RECEIPT: u8 = 4;
+//The first thing a client sends when fetching. See Request
+const FRAME_REQUEST: u8 = 5;
+//Ends the content without a checksum. Content sent straight from the file to the socket never
+//passes through the sender, and reading it again just for the checksum would undo the savings
+const FRAME_END_UNCHECKED: u8 = 6;
+//Type and length
+const FRAME_HEADER_SIZE: usize = 5;
+const FRAME_SIZE: usize = 64 * 1024;
+//Frames cost a couple of syscalls each when sending without copying, so those are larger
+const ZERO_COPY_FRAME_SIZE: usize = 4 * 1024 * 1024;
+//Messages are for people, so anything longer is someone talking the wrong protocol
+const MAX_ERROR_LEN: u32 = 64 * 1024;
+//The size of content that runs until the end frame, like an archive made while sending
+const UNKNOWN_SIZE: u64 = std::u64::MAX;
 
-impl<'a> Streamable<'a> for FileMessage<'a> {
-    fn read<T: Read + 'a>(mut stream: T) -> Result<Self> {
-        //Get the length of the name
-        let name_len = try!(stream.read_u32::<BigEndian>());
+//What the content of a message is
+const CONTENT_FILE: u8 = 0;
+//A tar archive of a directory
+const CONTENT_ARCHIVE: u8 = 1;
+//Where a symbolic link points
+const CONTENT_LINK: u8 = 2;
+//Nothing has paths longer than this
+const MAX_LINK_LEN: u64 = 4096;
 
-        //Get the name from the stream
-        let mut name_buff = Vec::with_capacity(name_len as usize); //@Expansion: Here we have the 32-bit again.
-        let name_read = try!(stream.readn(&mut name_buff, name_len as usize));
-        if name_len != name_read as u32 {
-            bail!(ErrorKind::IncompleteRead(name_read, name_len as usize));
-        }
-        let name = String::from_utf8(name_buff).unwrap(); //@Error: Make error
+//Codes sent along with errors, so the other side can tell them apart without reading the message
+pub const ERROR_OTHER: u16 = 0;
+pub const ERROR_UNKNOWN_FILE: u16 = 1;
+pub const ERROR_ABORTED: u16 = 2;
+pub const ERROR_UNAVAILABLE: u16 = 3;
+pub const ERROR_INVALID_RANGE: u16 = 4;
 
-        //Get the length of the file contents
-        let file_len = try!(stream.read_u32::<BigEndian>()); //@Expansion: u32. That's a direct limit on the size of files.
-                                                             //Currently we aren't aiming at
-                                                             //supporting large files, which makes
-                                                             //it ok.
-        //We aren't getting the file contents because we don't want to store it all in memory
-        return Ok(FileMessage {
-            name_size: name_len,
-            name: name,
-            size: file_len,
-            file: Box::new(stream),
-        });
-    }
+//Files can be fetched over several connections at once, each getting a part of the file. The
+//client asks for a number of parts, and the server decides how many it actually gets
+const MAX_PARTS: u16 = 16;
+//Smaller parts aren't worth the extra connection
+const MIN_PART_SIZE: u64 = 4 * 1024 * 1024;
 
-    fn write<T: Write + 'a>(&mut self, mut stream: &mut T) -> Result<usize>{
-        try!(stream.write_u32::<BigEndian>(self.name_size)); //@Error: Should this be handled differently?
-        try!(stream.write_all(self.name.as_bytes()));
-        try!(stream.write_u32::<BigEndian>(self.size));
-        try!(std::io::copy(&mut self.file, &mut stream));
-        return Ok(0);
-    }
+//How many parts the file is split into when the client asks for requested. Both sides work it
+//out the same way from what's in the request
+fn negotiate_parts(total: u64, requested: u16) -> u16 {
+    let useful = std::cmp::max(total / MIN_PART_SIZE, 1);
+    let parts = std::cmp::min(std::cmp::min(requested, MAX_PARTS) as u64, useful);
+    return std::cmp::max(parts, 1) as u16;
 }
 
-pub type Dict<'a> = Box<[&'a str]>;
+//Offset and size of a part. The parts are as close to equal as they get and cover the whole file
+fn split_part(total: u64, part: u16, parts: u16) -> (u64, u64) {
+    let start = (total as u128 * part as u128 / parts as u128) as u64;
+    let end = (total as u128 * (part as u128 + 1) / parts as u128) as u64;
+    return (start, end - start);
+}
 
-pub struct TransportPresenter<'a> {
-    dictionary: Dict<'a>,
-    dict_entries: u32,
+//Which bytes of a file to fetch. Ends are inclusive, like in HTTP ranges
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    //From an offset up to an end, or to the end of the file
+    From(u64, Option<u64>),
+    //The last so many bytes
+    Last(u64),
 }
 
-impl<'a> TransportPresenter<'a> {
-    pub fn new(dictionary: Dict<'a>, dict_entries: u32) -> Self {
-        return TransportPresenter {
-            dictionary: dictionary,
-            dict_entries: dict_entries,
-        };
+impl ByteRange {
+    pub fn whole() -> Self {
+        return ByteRange::From(0, None);
     }
 
-    pub fn present(&self, t: &Transport) -> Result<String> {
-        let parts = (t.max_state() as f64).log(self.dict_entries as f64).ceil() as u32;
-
-        let mut part_representation: Vec<&str> = Vec::with_capacity(parts as usize);
-
-        let mut remainder = t.state();
-        for _ in 0..parts {
-            let part = remainder % self.dict_entries;
-            remainder = remainder / self.dict_entries;
-            part_representation.push(self.dictionary[part as usize]);
-        }
-        return Ok(part_representation.join(" "));
+    //Parses ranges like 100-199, 1M- and -10K. Sizes can have the same suffixes as in parse_size
+    pub fn parse(s: &str) -> Result<ByteRange> {
+        let s = s.trim();
+        let dash = match s.find('-') {
+            Some(dash) => dash,
+            None => bail!(ErrorKind::InvalidRange(s.to_owned())),
+        };
+        let size = |x| parse_size(x).chain_err(|| ErrorKind::InvalidRange(s.to_owned()));
+        let (start, end) = (&s[..dash], &s[dash + 1..]);
+        let range = match (start.is_empty(), end.is_empty()) {
+            (true, true) => bail!(ErrorKind::InvalidRange(s.to_owned())),
+            (true, false) => ByteRange::Last(size(end)?),
+            (false, true) => ByteRange::From(size(start)?, None),
+            (false, false) => {
+                let (start, end) = (size(start)?, size(end)?);
+                if end < start {
+                    bail!(ErrorKind::InvalidRange(s.to_owned()));
+                }
+                ByteRange::From(start, Some(end))
+            }
+        };
+        return Ok(range);
     }
 
-    pub fn present_inv(&self, s: String) -> Result<ClientTransport> {
-        let mut res:  u32 = 0;
-        let mut part_count = 0;
-        for word in s.split(" ") {
-            if let Ok(val) = self.dictionary.binary_search_by(|p| {
-                //Flip the search to allow for cmp between String and &str
-                match word.cmp(p) {
-                    Ordering::Greater => Ordering::Less,
-                    Ordering::Less => Ordering::Greater,
-                    Ordering::Equal => Ordering::Equal,
+    //Offset and length of the range in a file of the given size. Ranges ending past the file
+    //are cut short, but ones starting past it don't fit at all
+    fn resolve(&self, total: u64) -> Result<(u64, u64)> {
+        return match *self {
+            ByteRange::From(start, end) => {
+                if start >= total && start != 0 {
+                    bail!(ErrorKind::RangeNotSatisfiable(start, total));
                 }
-            }) {
-                res += (val as u32) * (self.dict_entries.pow(part_count));
-                part_count += 1;
-            } else {
-                bail!(ErrorKind::InvalidTransport(word.to_owned()));
+                let end = end.map_or(total, |end| std::cmp::min(end.saturating_add(1), total));
+                Ok((start, end - start))
             }
-        }
-        return Ok(ClientTransport::new(res));
+            ByteRange::Last(count) => {
+                let count = std::cmp::min(count, total);
+                Ok((total - count, count))
+            }
+        };
     }
 }
 
-pub struct ServerTransport {
-    state: u32,
-    max_state: u32,
+//Sent by the client to say what it wants. Every connection of a transfer carries the same
+//transfer id and range, so the server can tell which of them belong together and split the
+//range the same way for each.
+//The frame has the file id as a big endian u32, the transfer id as a u64, then the part and the
+//number of parts asked for as u16s. Then comes the range, as a u8 that is 1 when counting from
+//the end, an offset and an end as u64s. No end is sent as u64::MAX. Last is a u8 bitmask of the
+//ids of the codecs the client can decompress.
+#[derive(Clone, Copy, Debug)]
+struct Request {
+    file: u32,
+    transfer: u64,
+    part: u16,
+    parts: u16,
+    range: ByteRange,
+    codecs: u8,
 }
 
-pub struct ClientTransport {
-    state: u32,
+impl Request {
+    fn read<R: Read>(stream: &mut R) -> Result<Request> {
+        if stream.read_u8()? != FRAME_REQUEST {
+            bail!(ErrorKind::InvalidFrame);
+        }
+        let file = stream.read_u32::<BigEndian>()?;
+        let transfer = stream.read_u64::<BigEndian>()?;
+        let part = stream.read_u16::<BigEndian>()?;
+        let parts = stream.read_u16::<BigEndian>()?;
+        if parts == 0 {
+            bail!(ErrorKind::InvalidFrame);
+        }
+        let from_end = stream.read_u8()?;
+        let offset = stream.read_u64::<BigEndian>()?;
+        let end = stream.read_u64::<BigEndian>()?;
+        let range = match (from_end, end) {
+            (0, std::u64::MAX) => ByteRange::From(offset, None),
+            (0, end) if end >= offset => ByteRange::From(offset, Some(end)),
+            (1, _) => ByteRange::Last(offset),
+            _ => bail!(ErrorKind::InvalidFrame),
+        };
+        let codecs = stream.read_u8()?;
+        return Ok(Request {
+            file: file,
+            transfer: transfer,
+            part: part,
+            parts: parts,
+            range: range,
+            codecs: codecs,
+        });
+    }
+
+    fn write<W: Write>(&self, stream: &mut W) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(35);
+        frame.write_u8(FRAME_REQUEST)?;
+        frame.write_u32::<BigEndian>(self.file)?;
+        frame.write_u64::<BigEndian>(self.transfer)?;
+        frame.write_u16::<BigEndian>(self.part)?;
+        frame.write_u16::<BigEndian>(self.parts)?;
+        let (from_end, offset, end) = match self.range {
+            ByteRange::From(offset, end) => (0, offset, end.unwrap_or(std::u64::MAX)),
+            ByteRange::Last(count) => (1, count, std::u64::MAX),
+        };
+        frame.write_u8(from_end)?;
+        frame.write_u64::<BigEndian>(offset)?;
+        frame.write_u64::<BigEndian>(end)?;
+        frame.write_u8(self.codecs)?;
+        return stream.write_all(&frame);
+    }
 }
 
-pub trait Transport {
-    fn state(&self) -> u32;
-    fn max_state(&self) -> u32;
+//Tell the other side why we are giving up on them. The message is the whole chain, since that's
+//what we would have printed ourselves
+fn write_error<W: Write>(stream: &mut W, code: u16, err: &Error) -> std::io::Result<()> {
+    let message = err.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(": ");
+    let message = &message.as_bytes()[..std::cmp::min(message.len(), MAX_ERROR_LEN as usize)];
+    let mut frame = Vec::with_capacity(7 + message.len());
+    frame.write_u8(FRAME_ERROR)?;
+    frame.write_u16::<BigEndian>(code)?;
+    frame.write_u32::<BigEndian>(message.len() as u32)?;
+    frame.extend_from_slice(message);
+    return stream.write_all(&frame);
 }
 
-impl ServerTransport {
-    fn new(state: u32, max_state: u32) -> Self {
-        return ServerTransport {
-            state: state,
-            max_state: max_state,
-        };
-    }
+//An error frame, after the type has been read
+#[derive(Debug)]
+struct RemoteError {
+    code: u16,
+    message: String,
 }
 
-impl Transport for ServerTransport {
-    fn state(&self) -> u32 {
-        return self.state;
+impl RemoteError {
+    fn read<R: Read>(stream: &mut R) -> std::io::Result<RemoteError> {
+        let code = stream.read_u16::<BigEndian>()?;
+        let len = stream.read_u32::<BigEndian>()?;
+        if len > MAX_ERROR_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Error message is too long"));
+        }
+        let mut message = Vec::with_capacity(len as usize);
+        if stream.readn(&mut message, len as usize)? != len as usize {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Error message was cut short"));
+        }
+        return Ok(RemoteError {
+            code: code,
+            message: String::from_utf8_lossy(&message).into_owned(),
+        });
     }
+}
 
-    fn max_state(&self) -> u32 {
-        return self.max_state;
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "The other side failed with code {}: {}", self.code, self.message);
     }
 }
 
-pub trait PartialTransport {
-    fn state(&self) -> u32;
+impl std::error::Error for RemoteError {
+    fn description(&self) -> &str {
+        return "The other side reported an error";
+    }
 }
 
-impl ClientTransport {
-    fn new(state: u32) -> Self {
-        return ClientTransport {
-            state: state,
-        };
+//Error frames in the content come out of the frame reader as io errors, since that's all Read
+//allows. This gets them back out.
+fn content_error(err: std::io::Error) -> Error {
+    if let Some(remote) = err.get_ref().and_then(|x| x.downcast_ref::<RemoteError>()) {
+        return ErrorKind::Remote(remote.code, remote.message.clone()).into();
+    }
+    if err.get_ref().map_or(false, |x| x.is::<ChecksumMismatch>()) {
+        return ErrorKind::ChecksumMismatch.into();
     }
+    return err.into();
 }
 
-impl PartialTransport for ClientTransport {
-    fn state(&self) -> u32 {
-        return self.state;
+//Only here so it can be told apart from other io errors. See content_error
+#[derive(Debug)]
+struct ChecksumMismatch;
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "Checksum mismatch");
     }
 }
 
-impl <T: Transport> PartialTransport for T {
-    fn state(&self) -> u32 {
-        return Transport::state(self);
+impl std::error::Error for ChecksumMismatch {
+    fn description(&self) -> &str {
+        return "Checksum mismatch";
     }
 }
 
-pub trait Transportable {
-    fn make_transport(&self) -> Result<ServerTransport>;
-    fn from_transport<T: PartialTransport>(t: T) -> Result<Self> where Self: std::marker::Sized;
+//Reads the file content back out of the frames. The end frame reads as the end of the file, but
+//only if the checksum matches what we got
+struct FrameReader<R> {
+    inner: R,
+    //What's left of the current data frame
+    left: u32,
+    hasher: crc32fast::Hasher,
+    done: bool,
+    //Set if the content ended without a checksum. Shared, since the reader ends up behind the
+    //decoder
+    unchecked: std::sync::Arc<std::sync::atomic::AtomicBool>,
 }
 
-impl Transportable for std::net::Ipv4Addr {
-    fn make_transport(&self) -> Result<ServerTransport> {
-        return Ok(ServerTransport::new(u32::from(self.clone()), std::u32::MAX));
+impl<R: Read> FrameReader<R> {
+    fn new(inner: R, unchecked: std::sync::Arc<std::sync::atomic::AtomicBool>) -> Self {
+        return FrameReader {
+            inner: inner,
+            left: 0,
+            hasher: crc32fast::Hasher::new(),
+            done: false,
+            unchecked: unchecked,
+        };
     }
+}
 
-    fn from_transport<T: PartialTransport>(t: T) -> Result<Self> {
-        return Ok(std::net::Ipv4Addr::from(t.state()));
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.left == 0 {
+            if self.done {
+                return Ok(0);
+            }
+            let frame = match self.inner.read_u8() {
+                Ok(frame) => frame,
+                Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "The sender hung up before the end of the file"));
+                }
+                Err(err) => return Err(err),
+            };
+            match frame {
+                FRAME_DATA => self.left = self.inner.read_u32::<BigEndian>()?,
+                FRAME_END => {
+                    let checksum = self.inner.read_u32::<BigEndian>()?;
+                    if checksum != self.hasher.clone().finalize() {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, ChecksumMismatch));
+                    }
+                    self.done = true;
+                }
+                FRAME_END_UNCHECKED => {
+                    self.unchecked.store(true, std::sync::atomic::Ordering::SeqCst);
+                    self.done = true;
+                }
+                FRAME_ERROR => {
+                    let err = RemoteError::read(&mut self.inner)?;
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, err));
+                }
+                _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Got a frame of unknown type")),
+            }
+        }
+        let len = std::cmp::min(buf.len(), self.left as usize);
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "The sender hung up before the end of the file"));
+        }
+        self.hasher.update(&buf[..read]);
+        self.left -= read as u32;
+        return Ok(read);
     }
 }
 
-#[derive(Clone)]
-pub struct FileInfo{
-    path: PathBuf,
-    len: u64,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    //We never got as far as checking, or the sender sent no checksum
+    Unchecked,
+    Verified,
+    Mismatch,
 }
 
-impl FileInfo {
-    fn new(path: PathBuf, len: u64) -> FileInfo {
-        return FileInfo {
-            path: path,
-            len: len,
-        }
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "{}", match *self {
+            Checksum::Unchecked => "unchecked",
+            Checksum::Verified => "verified",
+            Checksum::Mismatch => "checksum mismatch",
+        });
     }
+}
 
-    pub fn from_path(path: PathBuf) -> Result<FileInfo> {
-        let metadata = std::fs::metadata(&path)?;
-        return Ok(FileInfo::new(path, metadata.len()))
-    }
+//Sent back by the receiver once it is done with a file, so the sender knows how it went.
+//The frame has the success as a u8, the checksum status as a u8 and the bytes written as a big
+//endian u64.
+#[derive(Clone, Copy, Debug)]
+pub struct Receipt {
+    pub success: bool,
+    pub bytes: u64,
+    pub checksum: Checksum,
+}
 
-    pub fn open(&self) -> std::result::Result<std::fs::File, std::io::Error> {
-        return std::fs::File::open(&self.path);
+impl Receipt {
+    fn new() -> Self {
+        return Receipt {
+            success: false,
+            bytes: 0,
+            checksum: Checksum::Unchecked,
+        };
     }
-}
 
-//@Refactor: This is just private but should be refactored
-fn send_file<S: Write>(mut stream: &mut S, file: &FileInfo) -> Result<()> {
-    let filename = match file.path.file_name()
-        .and_then(|x| x.to_str())
-        .map(|x| x.to_owned()) {
-        Some(x) => x,
-        None => return Err(ErrorKind::PathConversion.into()),
-    };
+    fn read<R: Read>(stream: &mut R) -> Result<Receipt> {
+        match stream.read_u8()? {
+            FRAME_RECEIPT => {},
+            FRAME_ERROR => {
+                let err = RemoteError::read(stream)?;
+                bail!(ErrorKind::Remote(err.code, err.message));
+            }
+            _ => bail!(ErrorKind::InvalidFrame),
+        }
+        let success = stream.read_u8()? != 0;
+        let checksum = match stream.read_u8()? {
+            0 => Checksum::Unchecked,
+            1 => Checksum::Verified,
+            2 => Checksum::Mismatch,
+            _ => bail!(ErrorKind::InvalidFrame),
+        };
+        let bytes = stream.read_u64::<BigEndian>()?;
+        return Ok(Receipt {
+            success: success,
+            bytes: bytes,
+            checksum: checksum,
+        });
+    }
 
-    let mut message = FileMessage::new(filename, file.len as u32, try!(file.open()));
-    message.write(&mut stream)
-        .chain_err(|| ErrorKind::Serialization)?;
-    return Ok(());
+    fn write<W: Write>(&self, stream: &mut W) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(11);
+        frame.write_u8(FRAME_RECEIPT)?;
+        frame.write_u8(self.success as u8)?;
+        frame.write_u8(match self.checksum {
+            Checksum::Unchecked => 0,
+            Checksum::Verified => 1,
+            Checksum::Mismatch => 2,
+        })?;
+        frame.write_u64::<BigEndian>(self.bytes)?;
+        return stream.write_all(&frame);
+    }
 }
 
-pub struct FileRepository {
-    files: std::collections::HashMap<u32, FileInfo>,
-    pub interface: network::Interface,
-    next_id: u32,
+//The header has the name, the size of the whole file, where this part of it starts and how long it
+//is as big endian u64s, the number of parts the file is split into as a u16 and the id of the
+//codec the content is compressed with as a u8. Sizes are always before compression. Last is a u8
+//saying what the content is, one of the CONTENT_ constants
+struct FileMessage<'a> {
+    name_size: u32,
+    name: String,
+    total: u64,
+    offset: u64,
+    size: u64,
+    parts: u16,
+    codec: Codec,
+    content: u8,
+    file: Box<Read + Send + 'a>,
+    //Set once the content has ended without a checksum
+    unchecked: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    //Checked between frames when writing. Once it's set we give up with TransferAborted
+    abort: Option<&'a std::sync::atomic::AtomicBool>,
+    //Writing is kept under these
+    limits: Limits,
 }
 
-impl FileRepository {
-    pub fn new(interface: network::Interface) -> Self {
-        return FileRepository {
-            files: std::collections::HashMap::new(),
-            interface: interface,
-            next_id: 0,
+impl<'a> FileMessage<'a> {
+    fn new<T: Read + Send + 'a>(name: String, size: u64, stream: T) -> Self {
+        return FileMessage {
+            name_size:  name.len() as u32, //@Expansion: 32 bits is a lot, but maybe in the far flung future.
+            name: name,
+            total: size,
+            offset: 0,
+            size: size,
+            parts: 1,
+            codec: Codec::None,
+            content: CONTENT_FILE,
+            file: Box::new(stream),
+            unchecked: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            abort: None,
+            limits: Limits::new(),
         };
     }
 
-    pub fn add_file(&mut self, file: FileInfo) -> Result<ServerTransport> {
-        self.files.insert(self.next_id, file);
-        return self.interface.addr.make_transport();
+    fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
     }
 
-    fn get_file(&self, index: u32) -> Result<&FileInfo> {
-        return self.files.get(&index)
-            .ok_or_else(|| ErrorKind::UnknownFile(index).into());
+    //The content is an archive made while sending, so there's no telling how big it is
+    fn set_archive(&mut self) {
+        self.content = CONTENT_ARCHIVE;
+        self.total = UNKNOWN_SIZE;
+        self.size = UNKNOWN_SIZE;
     }
 
-    pub fn run(&self) -> Result<()> {
-        //@Expansion: Maybe don't use fixed ports
-        let listener = std::net::TcpListener::bind((self.interface.addr, 2222))
-            .chain_err(|| ErrorKind::Bind(self.interface.addr, 2222))?;
+    //The content is where a link points
+    fn set_link(&mut self) {
+        self.content = CONTENT_LINK;
+    }
 
-        for conn in listener.incoming() {
-            let mut stream = conn
-                .chain_err(|| ErrorKind::ServerConnection)?;
-            //TODO: I should read some sort of info about which file to get here
-            let file = self.get_file(0)
-                .chain_err(|| ErrorKind::SendFile(stream.peer_addr().unwrap()))?;
-            send_file(&mut stream, file)
-                .chain_err(|| ErrorKind::SendFile(stream.peer_addr().unwrap()))?;
-        }
-        return Ok(());
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
-}
 
-pub struct FileClient {
-}
+    //Only send part of a file. The stream should already be at the offset
+    fn set_part(&mut self, total: u64, offset: u64, parts: u16) {
+        self.total = total;
+        self.offset = offset;
+        self.parts = parts;
+    }
 
-impl FileClient{
-    pub fn new() -> Self {
-        return FileClient {
-        }
+    //A whole file in one piece
+    fn is_whole(&self) -> bool {
+        return self.offset == 0 && self.size == self.total;
     }
 
-    pub fn get_file<T: PartialTransport>(&self, transport: T, out_path: Option<std::path::PathBuf>) -> Result<()> {
-        let ip = std::net::Ipv4Addr::from_transport(transport)?;
-        println!("{} from ip {}",
-                 Green.paint("Downloading"),
-                 Yellow.paint(ip.to_string()));
-        //@Expansion: We can't time out right now. Use the net2::TcpBuilder?
-        //@Expansion: Maybe don't use fixed ports
-        let stream = std::net::TcpStream::connect((ip, 2222))
-            .chain_err(|| ErrorKind::ClientConnection(ip, 2222))?;
-        let mut message = FileMessage::read(stream)
-            .chain_err(|| ErrorKind::Fetch)?;
+    fn set_abort(&mut self, abort: &'a std::sync::atomic::AtomicBool) {
+        self.abort = Some(abort);
+    }
+}
 
+impl<'a> Streamable<'a> for FileMessage<'a> {
+    fn read<T: Read + Send + 'a>(mut stream: T) -> Result<Self> {
+        match stream.read_u8()? {
+            FRAME_FILE => {},
+            FRAME_ERROR => {
+                let err = RemoteError::read(&mut stream)?;
+                bail!(ErrorKind::Remote(err.code, err.message));
+            }
+            _ => bail!(ErrorKind::InvalidFrame),
+        }
 
-        let mut pb = ProgressBar::new(message.size as u64);
-        pb.set_units(Units::Bytes);
+        //Get the length of the name
+        let name_len = try!(stream.read_u32::<BigEndian>());
 
-        let new_path = out_path
-            .unwrap_or(std::path::PathBuf::from(&message.name));
+        //Get the name from the stream
+        let mut name_buff = Vec::with_capacity(name_len as usize); //@Expansion: Here we have the 32-bit again.
+        let name_read = try!(stream.readn(&mut name_buff, name_len as usize));
+        if name_len != name_read as u32 {
+            bail!(ErrorKind::IncompleteRead(name_read, name_len as usize));
+        }
+        let name = String::from_utf8(name_buff).unwrap(); //@Error: Make error
 
-        if new_path.exists() {
-            bail!(ErrorKind::FileExists(new_path));
+        //Get the length of the file contents and which part of it we are getting
+        let total = stream.read_u64::<BigEndian>()?;
+        let offset = stream.read_u64::<BigEndian>()?;
+        let size = stream.read_u64::<BigEndian>()?;
+        let parts = stream.read_u16::<BigEndian>()?;
+        if parts == 0 || offset.checked_add(size).map_or(true, |end| end > total) {
+            bail!(ErrorKind::InvalidFrame);
         }
+        let codec = match Codec::from_id(stream.read_u8()?) {
+            Some(codec) => codec,
+            None => bail!(ErrorKind::InvalidFrame),
+        };
+        let content = match stream.read_u8()? {
+            CONTENT_FILE => CONTENT_FILE,
+            CONTENT_ARCHIVE if parts == 1 && size == UNKNOWN_SIZE => CONTENT_ARCHIVE,
+            CONTENT_LINK if parts == 1 && size == total && size <= MAX_LINK_LEN => CONTENT_LINK,
+            _ => bail!(ErrorKind::InvalidFrame),
+        };
+        //We aren't getting the file contents because we don't want to store it all in memory
+        let unchecked = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = FrameReader::new(stream, unchecked.clone());
+        return Ok(FileMessage {
+            name_size: name_len,
+            name: name,
+            total: total,
+            offset: offset,
+            size: size,
+            parts: parts,
+            codec: codec,
+            content: content,
+            file: Box::new(compression::Decoder::new(codec, reader)?),
+            unchecked: unchecked,
+            abort: None,
+            limits: Limits::new(),
+        });
+    }
 
-        //TODO: Make some error wrapper
-        let mut file = std::fs::File::create(new_path)?;
+    fn write<T: Write + 'a>(&mut self, stream: &mut T) -> Result<usize>{
+        self.write_header(stream)?;
 
-        let mut buffer = [0u8; 8192];
-        loop{
-            let read = message.file.read(&mut buffer)
-                .chain_err(|| ErrorKind::ReadContent)?;
+        let throttled = Throttled::new(&mut *stream, self.limits.clone());
+        let mut encoder = compression::Encoder::new(self.codec, FrameWriter::new(throttled))?;
+        let mut buffer = vec![0u8; FRAME_SIZE];
+        //The file might have grown since we looked at it, but we only promised size bytes
+        let abort = self.abort;
+        let mut content = (&mut self.file).take(self.size);
+        let mut total = 0;
+        loop {
+            if is_set(abort) {
+                bail!(ErrorKind::TransferAborted);
+            }
+            let read = content.read(&mut buffer)?;
             if read == 0 {
                 break;
             }
-            pb.add(read as u64);
-            file.write(&mut buffer[0..read])
-                .chain_err(|| ErrorKind::WriteContent)?;
+            encoder.write_all(&buffer[..read])?;
+            total += read;
         }
+        encoder.finish()?.finish()?;
+        return Ok(total);
+    }
+}
+
+impl<'a> FileMessage<'a> {
+    fn write_header<T: Write>(&self, stream: &mut T) -> Result<()> {
+        try!(stream.write_u8(FRAME_FILE));
+        try!(stream.write_u32::<BigEndian>(self.name_size)); //@Error: Should this be handled differently?
+        try!(stream.write_all(self.name.as_bytes()));
+        try!(stream.write_u64::<BigEndian>(self.total));
+        try!(stream.write_u64::<BigEndian>(self.offset));
+        try!(stream.write_u64::<BigEndian>(self.size));
+        try!(stream.write_u16::<BigEndian>(self.parts));
+        try!(stream.write_u8(self.codec.id()));
+        try!(stream.write_u8(self.content));
         return Ok(());
     }
+
+    //Same as write, but the content is a tar archive of the entries, made as it goes out
+    fn write_archive<T: Write>(&self, stream: &mut T, entries: &[archive::Entry]) -> Result<()> {
+        self.write_header(stream)?;
+
+        let throttled = Throttled::new(&mut *stream, self.limits.clone());
+        let encoder = compression::Encoder::new(self.codec, FrameWriter::new(throttled))?;
+        let encoder = archive::write(entries, encoder, self.abort)?;
+        encoder.finish()?.finish()?;
+        return Ok(());
+    }
+
+    //Same as write, but the content goes straight from the page cache to the socket where the
+    //system lets us. Only content we copied ourselves gets a checksum.
+    fn write_zero_copy(&self, stream: &mut std::net::TcpStream, source: &std::fs::File) -> Result<usize> {
+        use std::os::unix::fs::FileExt;
+
+        let size = self.size;
+        let start = self.offset;
+        self.write_header(stream)?;
+        let mut zero_copy = true;
+        let mut hasher = crc32fast::Hasher::new();
+        //Whether any of the content went out without passing through the hasher
+        let mut unchecked = false;
+        let mut buffer = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            if is_set(self.abort) {
+                bail!(ErrorKind::TransferAborted);
+            }
+            let len = std::cmp::min(ZERO_COPY_FRAME_SIZE as u64, size - offset) as usize;
+            let mut header = [FRAME_DATA, 0, 0, 0, 0];
+            BigEndian::write_u32(&mut header[1..], len as u32);
+            stream.write_all(&header)?;
+
+            let mut sent = 0;
+            while sent < len {
+                let position = start + offset + sent as u64;
+                //Sending can be partial, so the limits are paid what actually went out
+                let chunk = self.limits.chunk(len - sent);
+                if zero_copy {
+                    match network::sendfile(stream, source, position, chunk) {
+                        Ok(0) => bail!(ErrorKind::IncompleteRead((position - start) as usize, size as usize)),
+                        Ok(written) => {
+                            self.limits.take(written);
+                            sent += written;
+                            unchecked = true;
+                        }
+                        //Not every file or system can do it. Then we copy from here on
+                        Err(ref err) if network::is_unsupported(err) => zero_copy = false,
+                        Err(err) => return Err(err.into()),
+                    }
+                } else {
+                    buffer.resize(chunk, 0);
+                    let read = source.read_at(&mut buffer, position)?;
+                    if read == 0 {
+                        bail!(ErrorKind::IncompleteRead((position - start) as usize, size as usize));
+                    }
+                    stream.write_all(&buffer[..read])?;
+                    self.limits.take(read);
+                    hasher.update(&buffer[..read]);
+                    sent += read;
+                }
+            }
+            offset += len as u64;
+        }
+
+        if unchecked {
+            stream.write_all(&[FRAME_END_UNCHECKED])?;
+        } else {
+            write_end(stream, hasher.finalize())?;
+        }
+        return Ok(size as usize);
+    }
+}
+
+//Puts what is written to it into data frames, keeping a checksum of it all for the end frame
+struct FrameWriter<W: Write> {
+    inner: W,
+    //Starts with room for the frame header, so each frame is a single write
+    buffer: Vec<u8>,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn new(inner: W) -> Self {
+        let mut buffer = Vec::with_capacity(FRAME_HEADER_SIZE + FRAME_SIZE);
+        buffer.resize(FRAME_HEADER_SIZE, 0);
+        return FrameWriter {
+            inner: inner,
+            buffer: buffer,
+            hasher: crc32fast::Hasher::new(),
+        };
+    }
+
+    fn write_frame(&mut self) -> std::io::Result<()> {
+        let len = self.buffer.len() - FRAME_HEADER_SIZE;
+        if len == 0 {
+            return Ok(());
+        }
+        self.buffer[0] = FRAME_DATA;
+        BigEndian::write_u32(&mut self.buffer[1..FRAME_HEADER_SIZE], len as u32);
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.truncate(FRAME_HEADER_SIZE);
+        return Ok(());
+    }
+
+    //Sends what's left and ends the content
+    fn finish(mut self) -> Result<W> {
+        self.write_frame()?;
+        let checksum = self.hasher.finalize();
+        write_end(&mut self.inner, checksum)?;
+        return Ok(self.inner);
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = std::cmp::min(buf.len(), FRAME_HEADER_SIZE + FRAME_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        self.hasher.update(&buf[..len]);
+        if self.buffer.len() == FRAME_HEADER_SIZE + FRAME_SIZE {
+            self.write_frame()?;
+        }
+        return Ok(len);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_frame()?;
+        return self.inner.flush();
+    }
+}
+
+fn is_set(flag: Option<&std::sync::atomic::AtomicBool>) -> bool {
+    return flag.map_or(false, |x| x.load(std::sync::atomic::Ordering::SeqCst));
+}
+
+fn write_end<T: Write>(stream: &mut T, checksum: u32) -> Result<()> {
+    let mut frame = [FRAME_END, 0, 0, 0, 0];
+    BigEndian::write_u32(&mut frame[1..], checksum);
+    stream.write_all(&frame)?;
+    return Ok(());
+}
+
+pub struct TransportPresenter<'a> {
+    dictionary: Dictionary<'a>,
+}
+
+impl<'a> TransportPresenter<'a> {
+    pub fn new(dictionary: Dictionary<'a>) -> Self {
+        return TransportPresenter {
+            dictionary: dictionary,
+        };
+    }
+
+    pub fn dictionary(&self) -> Dictionary<'a> {
+        return self.dictionary;
+    }
+
+    pub fn present(&self, t: &Transport) -> Result<String> {
+        let dict_entries = self.dictionary.len();
+
+        //Every state up to the max has to be representable, so the number of words is the number
+        //of digits the max state has in base dict_entries.
+        let mut max_state = max_payload(t.state_bits());
+        let mut parts = 0;
+        while !is_zero(&max_state) {
+            divide_payload(&mut max_state, dict_entries);
+            parts += 1;
+        }
+        let parts = std::cmp::max(parts, 1);
+
+        let mut part_representation: Vec<&str> = Vec::with_capacity(parts as usize);
+
+        let mut remainder = t.state().to_vec();
+        for _ in 0..parts {
+            let part = divide_payload(&mut remainder, dict_entries);
+            part_representation.push(self.dictionary.get(part));
+        }
+        return Ok(part_representation.join(" "));
+    }
+
+    pub fn present_inv(&self, s: String) -> Result<ClientTransport> {
+        let words = split_key(&s);
+        if words.is_empty() {
+            bail!(ErrorKind::InvalidTransport(s));
+        }
+
+        let mut values = Vec::with_capacity(words.len());
+        for (index, &(column, ref word)) in words.iter().enumerate() {
+            if let Some(val) = self.dictionary.find(word) {
+                values.push(val);
+            } else {
+                bail!(ErrorKind::UnknownWord(word.clone(), index + 1, column));
+            }
+        }
+
+        //The first word is the least significant digit
+        let mut res = Vec::new();
+        for val in values.into_iter().rev() {
+            multiply_add_payload(&mut res, self.dictionary.len(), val);
+        }
+        return Ok(ClientTransport::new(res));
+    }
+}
+
+//Keys get pasted from all sorts of places, so we accept most things people put between words
+pub fn is_key_separator(c: char) -> bool {
+    return c.is_whitespace() || match c {
+        '-' | ',' | '.' | '"' | '\'' | '`' | '\u{2018}' | '\u{2019}' | '\u{201C}' | '\u{201D}' => true,
+        _ => false,
+    };
+}
+
+//Split a key into lowercase words along with the column (starting from 1) each word starts at
+fn split_key(key: &str) -> Vec<(usize, String)> {
+    let mut words = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    for (column, c) in key.chars().enumerate() {
+        if is_key_separator(c) {
+            if let Some(word) = current.take() {
+                words.push(word);
+            }
+        } else {
+            current.get_or_insert_with(|| (column + 1, String::new()))
+                .1.extend(c.to_lowercase());
+        }
+    }
+    if let Some(word) = current {
+        words.push(word);
+    }
+    return words;
+}
+
+//Renders text as a QR code in the terminal. Every character covers two rows of modules using the
+//unicode half blocks. The light modules are drawn, since most terminals have a dark background.
+pub struct QrPresenter {
+    quiet_zone: usize,
+}
+
+impl QrPresenter {
+    pub fn new() -> Self {
+        return QrPresenter {
+            quiet_zone: 4,
+        };
+    }
+
+    pub fn present(&self, data: &str) -> Result<String> {
+        let code = QrCode::new(data.as_bytes())
+            .chain_err(|| ErrorKind::QrEncode(data.to_owned()))?;
+        let width = code.width();
+        let colors = code.to_colors();
+        let size = width + 2 * self.quiet_zone;
+
+        let light = |x: usize, y: usize| -> bool {
+            if x < self.quiet_zone || y < self.quiet_zone {
+                return true;
+            }
+            let (x, y) = (x - self.quiet_zone, y - self.quiet_zone);
+            if x >= width || y >= width {
+                return true;
+            }
+            return colors[y * width + x] == Color::Light;
+        };
+
+        let mut res = String::with_capacity(size * (size / 2 + 1) * 3);
+        for y in (0..size).filter(|y| y % 2 == 0) {
+            for x in 0..size {
+                let top = light(x, y);
+                let bottom = y + 1 >= size || light(x, y + 1);
+                res.push(match (top, bottom) {
+                    (true, true) => '\u{2588}',
+                    (true, false) => '\u{2580}',
+                    (false, true) => '\u{2584}',
+                    (false, false) => ' ',
+                });
+            }
+            res.push('\n');
+        }
+        return Ok(res);
+    }
+}
+
+//Payloads are arbitrary size big endian unsigned integers. We only ever need to divide and
+//multiply them by the size of the dictionary, so a full bignum library isn't worth it.
+
+fn max_payload(bits: u32) -> Vec<u8> {
+    let mut payload = vec![0xFFu8; ((bits + 7) / 8) as usize];
+    if bits % 8 != 0 {
+        payload[0] = 0xFF >> (8 - bits % 8);
+    }
+    return payload;
+}
+
+fn is_zero(payload: &[u8]) -> bool {
+    return payload.iter().all(|x| *x == 0);
+}
+
+//Divides the payload in place and returns the remainder
+fn divide_payload(payload: &mut [u8], divisor: u32) -> u32 {
+    let mut remainder: u64 = 0;
+    for byte in payload.iter_mut() {
+        let current = (remainder << 8) | *byte as u64;
+        *byte = (current / divisor as u64) as u8;
+        remainder = current % divisor as u64;
+    }
+    return remainder as u32;
+}
+
+fn multiply_add_payload(payload: &mut Vec<u8>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for byte in payload.iter_mut().rev() {
+        let current = *byte as u64 * factor as u64 + carry;
+        *byte = current as u8;
+        carry = current >> 8;
+    }
+    while carry != 0 {
+        payload.insert(0, carry as u8);
+        carry = carry >> 8;
+    }
+}
+
+//Fit a payload into exactly len bytes, failing if it's too large
+fn fixed_payload(payload: &[u8], len: usize) -> Result<Vec<u8>> {
+    let significant = payload.iter()
+        .position(|x| *x != 0)
+        .map(|start| &payload[start..])
+        .unwrap_or(&[]);
+    if significant.len() > len {
+        bail!(ErrorKind::TransportSize(len as u32 * 8));
+    }
+    let mut res = vec![0u8; len - significant.len()];
+    res.extend_from_slice(significant);
+    return Ok(res);
+}
+
+pub struct ServerTransport {
+    state: Vec<u8>,
+    state_bits: u32,
+}
+
+pub struct ClientTransport {
+    state: Vec<u8>,
+}
+
+pub trait Transport {
+    //Big endian payload
+    fn state(&self) -> &[u8];
+    //How many bits of the payload the key has to be able to represent
+    fn state_bits(&self) -> u32;
+}
+
+impl ServerTransport {
+    fn new(state: Vec<u8>, state_bits: u32) -> Self {
+        return ServerTransport {
+            state: state,
+            state_bits: state_bits,
+        };
+    }
+}
+
+impl Transport for ServerTransport {
+    fn state(&self) -> &[u8] {
+        return &self.state;
+    }
+
+    fn state_bits(&self) -> u32 {
+        return self.state_bits;
+    }
+}
+
+pub trait PartialTransport {
+    fn state(&self) -> &[u8];
+    //Word keys never carry the port, so they all use the default
+    fn port(&self) -> u16 {
+        return DEFAULT_PORT;
+    }
+}
+
+impl ClientTransport {
+    fn new(state: Vec<u8>) -> Self {
+        return ClientTransport {
+            state: state,
+        };
+    }
+}
+
+impl PartialTransport for ClientTransport {
+    fn state(&self) -> &[u8] {
+        return &self.state;
+    }
+}
+
+impl <T: Transport> PartialTransport for T {
+    fn state(&self) -> &[u8] {
+        return Transport::state(self);
+    }
+}
+
+//A plain address used in place of the words. Accepts ip, ip:port, host, host:port and send:// uris
+pub struct DirectTransport {
+    state: [u8; 4],
+    port: u16,
+}
+
+impl DirectTransport {
+    pub const SCHEME: &'static str = "send://";
+
+    pub fn new(ip: std::net::Ipv4Addr, port: u16) -> Self {
+        return DirectTransport {
+            state: ip.octets(),
+            port: port,
+        };
+    }
+
+    //Whether the key can only be meant as an address. Plain hostnames can also be words, so
+    //those have to be tried after the dictionary
+    pub fn is_direct(s: &str) -> bool {
+        let s = s.trim();
+        return s.starts_with(DirectTransport::SCHEME)
+            || s.contains(':')
+            || s.parse::<std::net::Ipv4Addr>().is_ok();
+    }
+
+    pub fn parse(s: &str) -> Result<DirectTransport> {
+        return DirectTransport::parse_with_port(s, DEFAULT_PORT);
+    }
+
+    pub fn parse_with_port(s: &str, default_port: u16) -> Result<DirectTransport> {
+        let trimmed = s.trim();
+        let addr = if trimmed.starts_with(DirectTransport::SCHEME) {
+            &trimmed[DirectTransport::SCHEME.len()..]
+        } else {
+            trimmed
+        };
+        let addr = addr.trim_end_matches('/');
+
+        let (host, port) = match addr.rfind(':') {
+            Some(i) => {
+                let port = addr[i + 1..].parse::<u16>()
+                    .chain_err(|| ErrorKind::InvalidAddress(s.to_owned()))?;
+                (&addr[..i], port)
+            },
+            None => (addr, default_port),
+        };
+        if host.is_empty() {
+            bail!(ErrorKind::InvalidAddress(s.to_owned()));
+        }
+
+        let ip = match host.parse::<std::net::Ipv4Addr>() {
+            Ok(ip) => ip,
+            Err(_) => {
+                use std::net::ToSocketAddrs;
+                //@Expansion: Only ipv4 for now, so skip everything else the host resolves to
+                (host, port).to_socket_addrs()
+                    .chain_err(|| ErrorKind::ResolveHost(host.to_owned()))?
+                    .filter_map(|x| match x {
+                        std::net::SocketAddr::V4(x) => Some(*x.ip()),
+                        std::net::SocketAddr::V6(_) => None,
+                    })
+                    .next()
+                    .ok_or_else(|| Error::from(ErrorKind::ResolveHost(host.to_owned())))?
+            }
+        };
+        return Ok(DirectTransport::new(ip, port));
+    }
+
+    pub fn uri(&self) -> String {
+        return format!("{}{}", DirectTransport::SCHEME, self);
+    }
+
+    pub fn socket_addr(&self) -> std::net::SocketAddrV4 {
+        let ip = std::net::Ipv4Addr::new(self.state[0], self.state[1], self.state[2], self.state[3]);
+        return std::net::SocketAddrV4::new(ip, self.port);
+    }
+}
+
+impl std::fmt::Display for DirectTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "{}", self.socket_addr());
+    }
+}
+
+//Turns whatever the user gave as a key into somewhere to connect. That's either an address or
+//words, which might only hold the host part of the address.
+pub fn resolve_key(presenter: &TransportPresenter, key: &str, subnet: bool) -> Result<Vec<std::net::SocketAddrV4>> {
+    if DirectTransport::is_direct(key) {
+        return Ok(vec![DirectTransport::parse(key)?.socket_addr()]);
+    }
+
+    let transport = match presenter.present_inv(key.to_owned()) {
+        Ok(transport) => transport,
+        Err(err) => {
+            //A single unknown word might be a hostname
+            if key.split_whitespace().count() == 1 {
+                if let Ok(transport) = DirectTransport::parse(key) {
+                    return Ok(vec![transport.socket_addr()]);
+                }
+            }
+            return Err(err);
+        }
+    };
+
+    if subnet && !RelayKey::is_relay_key(&transport) {
+        let port = transport.port();
+        let interfaces = network::interfaces()
+            .chain_err(|| ErrorKind::Enumeration)?;
+        let (ip, interface) = HostAddr::resolve(transport, &interfaces)?;
+        info!("Resolved subnet key through interface {}", interface.name);
+        return Ok(vec![std::net::SocketAddrV4::new(ip, port)]);
+    }
+    return candidates(transport);
+}
+
+//The code to meet the server under at the relay, for keys the server gave out for the relay
+pub fn resolve_relay_code(presenter: &TransportPresenter, key: &str) -> Option<u64> {
+    if DirectTransport::is_direct(key) {
+        return None;
+    }
+    return presenter.present_inv(key.to_owned()).ok()
+        .and_then(|transport| RelayKey::from_transport(transport).ok())
+        .map(|key| key.code);
+}
+
+impl PartialTransport for DirectTransport {
+    fn state(&self) -> &[u8] {
+        return &self.state;
+    }
+
+    fn port(&self) -> u16 {
+        return self.port;
+    }
+}
+
+pub trait Transportable {
+    fn make_transport(&self) -> Result<ServerTransport>;
+    fn from_transport<T: PartialTransport>(t: T) -> Result<Self> where Self: std::marker::Sized;
+}
+
+impl Transportable for std::net::Ipv4Addr {
+    fn make_transport(&self) -> Result<ServerTransport> {
+        return Ok(ServerTransport::new(self.octets().to_vec(), 32));
+    }
+
+    fn from_transport<T: PartialTransport>(t: T) -> Result<Self> {
+        let state = fixed_payload(t.state(), 4)?;
+        return Ok(std::net::Ipv4Addr::new(state[0], state[1], state[2], state[3]));
+    }
+}
+
+impl Transportable for std::net::SocketAddrV4 {
+    fn make_transport(&self) -> Result<ServerTransport> {
+        let mut state = self.ip().octets().to_vec();
+        state.write_u16::<BigEndian>(self.port())?;
+        return Ok(ServerTransport::new(state, 48));
+    }
+
+    fn from_transport<T: PartialTransport>(t: T) -> Result<Self> {
+        let state = fixed_payload(t.state(), 6)?;
+        let ip = std::net::Ipv4Addr::new(state[0], state[1], state[2], state[3]);
+        let port = (&state[4..]).read_u16::<BigEndian>()?;
+        return Ok(std::net::SocketAddrV4::new(ip, port));
+    }
+}
+
+//All the addresses of a server in one key, so whoever fetches doesn't have to guess which
+//network they share. The payload is the number of addresses followed by the addresses. The count
+//is never zero, so the length of the payload can be recovered from the words.
+pub struct AddressList {
+    addrs: Vec<std::net::Ipv4Addr>,
+}
+
+impl AddressList {
+    pub fn new(addrs: Vec<std::net::Ipv4Addr>) -> Self {
+        return AddressList {
+            addrs: addrs,
+        };
+    }
+
+    pub fn addrs(&self) -> &[std::net::Ipv4Addr] {
+        return &self.addrs;
+    }
+
+    //A single address never needs more than 4 bytes, so anything larger has to be a list
+    pub fn is_list<T: PartialTransport>(t: &T) -> bool {
+        return t.state().iter().skip_while(|x| **x == 0).count() > 4;
+    }
+}
+
+impl Transportable for AddressList {
+    fn make_transport(&self) -> Result<ServerTransport> {
+        if self.addrs.is_empty() || self.addrs.len() > std::u8::MAX as usize {
+            bail!(ErrorKind::InvalidAddressList);
+        }
+        let mut state = vec![self.addrs.len() as u8];
+        for addr in &self.addrs {
+            state.extend_from_slice(&addr.octets());
+        }
+        let bits = state.len() as u32 * 8;
+        return Ok(ServerTransport::new(state, bits));
+    }
+
+    fn from_transport<T: PartialTransport>(t: T) -> Result<Self> {
+        let state = t.state().iter()
+            .skip_while(|x| **x == 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        if state.len() < 5 || (state.len() - 1) % 4 != 0 || state[0] as usize != (state.len() - 1) / 4 {
+            bail!(ErrorKind::InvalidAddressList);
+        }
+        let addrs = state[1..].chunks(4)
+            .map(|x| std::net::Ipv4Addr::new(x[0], x[1], x[2], x[3]))
+            .collect();
+        return Ok(AddressList::new(addrs));
+    }
+}
+
+//An address along with the code the server waits for clients under at the relay. The code is
+//random, so it can't be guessed from the address, and servers behind different NATs with the same
+//private address don't meet each other's clients. The payload is a marker, the code and then the
+//address. No address list of that length starts with the marker, so the two can't be mixed up.
+pub struct RelayKey {
+    addr: std::net::Ipv4Addr,
+    code: u64,
+}
+
+const RELAY_KEY_MARKER: u8 = 0xFF;
+const RELAY_KEY_LEN: usize = 13;
+
+impl RelayKey {
+    pub fn new(addr: std::net::Ipv4Addr, code: u64) -> Self {
+        return RelayKey {
+            addr: addr,
+            code: code,
+        };
+    }
+
+    pub fn addr(&self) -> std::net::Ipv4Addr {
+        return self.addr;
+    }
+
+    pub fn code(&self) -> u64 {
+        return self.code;
+    }
+
+    pub fn is_relay_key<T: PartialTransport>(t: &T) -> bool {
+        let significant = t.state().iter().skip_while(|x| **x == 0).collect::<Vec<_>>();
+        return significant.len() == RELAY_KEY_LEN && *significant[0] == RELAY_KEY_MARKER;
+    }
+}
+
+impl Transportable for RelayKey {
+    fn make_transport(&self) -> Result<ServerTransport> {
+        let mut state = vec![RELAY_KEY_MARKER];
+        state.write_u64::<BigEndian>(self.code)?;
+        state.extend_from_slice(&self.addr.octets());
+        return Ok(ServerTransport::new(state, RELAY_KEY_LEN as u32 * 8));
+    }
+
+    fn from_transport<T: PartialTransport>(t: T) -> Result<Self> {
+        if !RelayKey::is_relay_key(&t) {
+            bail!(ErrorKind::NoRelayCode);
+        }
+        let state = fixed_payload(t.state(), RELAY_KEY_LEN)?;
+        let code = (&state[1..9]).read_u64::<BigEndian>()?;
+        return Ok(RelayKey::new(std::net::Ipv4Addr::new(state[9], state[10], state[11], state[12]), code));
+    }
+}
+
+//What the server and client meet on at the relay
+fn relay_code(code: u64) -> String {
+    return format!("{:016x}", code);
+}
+
+//Every address a key points at. Most keys hold a single address, but servers can also hand out
+//one key with all of theirs.
+pub fn candidates<T: PartialTransport>(t: T) -> Result<Vec<std::net::SocketAddrV4>> {
+    let port = t.port();
+    let addrs = if RelayKey::is_relay_key(&t) {
+        vec![RelayKey::from_transport(t)?.addr]
+    } else if AddressList::is_list(&t) {
+        AddressList::from_transport(t)?.addrs
+    } else {
+        vec![std::net::Ipv4Addr::from_transport(t)?]
+    };
+    return Ok(addrs.into_iter()
+        .map(|x| std::net::SocketAddrV4::new(x, port))
+        .collect());
+}
+
+//An address where only the host part goes into the key. The fetching side fills the network
+//part back in from its own interface on the same subnet.
+pub struct HostAddr {
+    addr: std::net::Ipv4Addr,
+    netmask: std::net::Ipv4Addr,
+}
+
+impl HostAddr {
+    pub fn new(addr: std::net::Ipv4Addr, netmask: std::net::Ipv4Addr) -> Self {
+        return HostAddr {
+            addr: addr,
+            netmask: netmask,
+        };
+    }
+
+    pub fn from_interface(interface: &network::Interface) -> Self {
+        return HostAddr::new(interface.addr, interface.netmask);
+    }
+
+    pub fn make_transport(&self) -> Result<ServerTransport> {
+        let host = u32::from(self.addr) & !u32::from(self.netmask);
+        let host_bits = u32::from(self.netmask).count_zeros();
+        return Ok(ServerTransport::new(std::net::Ipv4Addr::from(host).octets().to_vec(), host_bits));
+    }
+
+    //Find the interface the host part fits in and rebuild the full address from it. Loopback
+    //interfaces are only used when nothing else fits.
+    pub fn resolve<T: PartialTransport>(t: T, interfaces: &[network::Interface]) -> Result<(std::net::Ipv4Addr, &network::Interface)> {
+        let state = fixed_payload(t.state(), 4)?;
+        let host = u32::from(std::net::Ipv4Addr::new(state[0], state[1], state[2], state[3]));
+
+        let mut candidates = interfaces.iter()
+            .filter(|x| host & u32::from(x.netmask) == 0)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|x| x.addr.is_loopback());
+
+        return candidates.first()
+            .map(|x| (std::net::Ipv4Addr::from(u32::from(x.network()) | host), *x))
+            .ok_or_else(|| ErrorKind::NoMatchingInterface(host).into());
+    }
+}
+
+#[derive(Clone)]
+pub struct FileInfo{
+    path: PathBuf,
+    len: u64,
+    //Directories are sent as archives, leaving out what the filter says. Preserved links are
+    //sent as where they point
+    kind: archive::Kind,
+    filter: archive::Filter,
+}
+
+impl FileInfo {
+    fn new(path: PathBuf, len: u64, kind: archive::Kind) -> FileInfo {
+        return FileInfo {
+            len: len,
+            kind: kind,
+            filter: archive::Filter::new(&path),
+            path: path,
+        }
+    }
+
+    //If path is a link, links says what to do with it. Devices and FIFOs have no size to send,
+    //so they only go as part of a directory
+    pub fn from_path(path: PathBuf, links: archive::Links) -> Result<FileInfo> {
+        let (metadata, kind) = match archive::inspect(&path, links, false)? {
+            Some(found) => found,
+            None => bail!(ErrorKind::SkippedLink(path)),
+        };
+        let len = match kind {
+            archive::Kind::Symlink(ref target) => target.as_os_str().len() as u64,
+            _ => metadata.len(),
+        };
+        return Ok(FileInfo::new(path, len, kind))
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        return &self.path;
+    }
+
+    pub fn size(&self) -> u64 {
+        return self.len;
+    }
+
+    pub fn kind(&self) -> &archive::Kind {
+        return &self.kind;
+    }
+
+    pub fn is_dir(&self) -> bool {
+        return self.kind == archive::Kind::Dir;
+    }
+
+    //Only content that is all there up front can be split into parts and ranges
+    fn is_whole_only(&self) -> bool {
+        return match self.kind {
+            archive::Kind::Dir | archive::Kind::Symlink(_) => true,
+            _ => false,
+        };
+    }
+
+    pub fn set_filter(&mut self, filter: archive::Filter) {
+        self.filter = filter;
+    }
+
+    //What a download of the directory would have in it right now, and what it would leave out
+    pub fn entries(&self) -> Result<(Vec<archive::Entry>, Vec<archive::Skipped>)> {
+        return archive::walk(&self.path, &self.filter);
+    }
+
+    //What the other side calls it. Paths like . only have a name once they are resolved
+    fn name(&self) -> Result<String> {
+        let path = match self.path.file_name() {
+            Some(_) => self.path.clone(),
+            None => self.path.canonicalize()?,
+        };
+        return match path.file_name().and_then(|x| x.to_str()) {
+            Some(x) => Ok(x.to_owned()),
+            None => Err(ErrorKind::PathConversion.into()),
+        };
+    }
+
+    pub fn open(&self) -> std::result::Result<std::fs::File, std::io::Error> {
+        return std::fs::File::open(&self.path);
+    }
+}
+
+//@Refactor: This is just private but should be refactored
+//Sends size bytes of the file from offset, as one of parts. The content is compressed with the
+//best of the codecs the other side can handle, unless it doesn't look like it's worth it. Sending
+//stops as soon as the abort flag is set. Zero copy has to be asked for, since the content goes
+//without a checksum then
+fn send_file(stream: &mut std::net::TcpStream, file: &FileInfo, offset: u64, size: u64, parts: u16, codecs: u8, zero_copy: bool, abort: Option<&std::sync::atomic::AtomicBool>, limits: Limits) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let filename = file.name()?;
+
+    //Every part gets a handle of its own, so they don't fight over the position
+    let mut source = try!(file.open());
+    //Pipes and the like can only be read once, so those can't be sampled or sent without copying
+    let regular = source.metadata()?.is_file();
+    let mut codec = Codec::negotiate(codecs);
+    if codec != Codec::None && regular {
+        let mut sample = vec![0u8; std::cmp::min(compression::SAMPLE_SIZE as u64, size) as usize];
+        let read = source.read_at(&mut sample, offset)?;
+        if !compression::is_compressible(&sample[..read]) {
+            codec = Codec::None;
+        }
+    }
+    let zero_copy = zero_copy && regular && codec == Codec::None;
+    if !zero_copy && offset != 0 {
+        source.seek(std::io::SeekFrom::Start(offset))?;
+    }
+    let mut message = FileMessage::new(filename, size, &source);
+    message.set_part(file.len, offset, parts);
+    message.set_codec(codec);
+    message.set_limits(limits);
+    if let Some(abort) = abort {
+        message.set_abort(abort);
+    }
+    let res = if zero_copy {
+        message.write_zero_copy(stream, &source)
+    } else {
+        message.write(stream)
+    };
+    res.chain_err(|| ErrorKind::Serialization)?;
+    return Ok(());
+}
+
+//Sends where a link points, for the other side to make a link of its own
+fn send_link(stream: &mut std::net::TcpStream, file: &FileInfo, target: &std::path::Path, limits: Limits) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let target = target.as_os_str().as_bytes();
+    if target.len() as u64 > MAX_LINK_LEN {
+        bail!(ErrorKind::UnsupportedFile(file.path.clone()));
+    }
+    let mut message = FileMessage::new(file.name()?, target.len() as u64, target);
+    message.set_link();
+    message.set_limits(limits);
+    message.write(stream)
+        .chain_err(|| ErrorKind::Serialization)?;
+    return Ok(0);
+}
+
+//Sends a directory as a tar archive, compressed with the best codec the other side can handle.
+//What's in the directory is looked at anew for every download. Returns how much of it is content
+fn send_archive(stream: &mut std::net::TcpStream, file: &FileInfo, codecs: u8, abort: Option<&std::sync::atomic::AtomicBool>, limits: Limits) -> Result<u64> {
+    let (entries, _) = file.entries()?;
+    let size = entries.iter()
+        .filter(|x| x.kind == archive::Kind::File)
+        .map(|x| x.metadata.len())
+        .sum();
+    let mut message = FileMessage::new(file.name()?, 0, std::io::empty());
+    message.set_archive();
+    message.set_codec(Codec::negotiate(codecs));
+    message.set_limits(limits);
+    if let Some(abort) = abort {
+        message.set_abort(abort);
+    }
+    message.write_archive(stream, &entries)
+        .chain_err(|| ErrorKind::Serialization)?;
+    return Ok(size);
+}
+
+//Creates the file a download goes into. The name from the message is used unless we are told
+//where to put it
+//Where to store what the other side sent, when we weren't told. The name comes from the sender,
+//so it has to be a plain name that stays in the current directory
+fn output_path(name: &str, out_path: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(out_path) = out_path {
+        return Ok(out_path);
+    }
+    let path = std::path::Path::new(name);
+    match path.file_name() {
+        Some(file_name) if path.as_os_str() == file_name => return Ok(PathBuf::from(file_name)),
+        _ => bail!(ErrorKind::UnsafeName(name.to_owned())),
+    }
+}
+
+fn create_output(name: &str, out_path: Option<PathBuf>) -> Result<(PathBuf, std::fs::File)> {
+    let new_path = output_path(name, out_path)?;
+
+    //TODO: Make some error wrapper
+    let file = match std::fs::OpenOptions::new().write(true).create_new(true).open(&new_path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => bail!(ErrorKind::FileExists(new_path)),
+        Err(err) => return Err(err.into()),
+    };
+    return Ok((new_path, file));
+}
+
+//Reserve room for the whole file up front. Only running out of space is worth failing over
+fn preallocate(file: &std::fs::File, size: u64) -> Result<()> {
+    if let Err(err) = disk::preallocate(file, size) {
+        if disk::is_out_of_space(&err) {
+            return Err(err).chain_err(|| ErrorKind::WriteContent);
+        }
+        info!("Failed preallocating {} bytes: {}", size, err);
+    }
+    return Ok(());
+}
+
+type Progress = std::sync::Mutex<ProgressBar<std::io::Stdout>>;
+
+fn progress_bar(size: u64) -> Progress {
+    let mut pb = ProgressBar::new(size);
+    pb.set_units(Units::Bytes);
+    pb.set_max_refresh_rate(Some(std::time::Duration::from_millis(PROGRESS_INTERVAL_MILLIS)));
+    return std::sync::Mutex::new(pb);
+}
+
+//Writes a whole file from a message to disk. How it went is filled into the receipt, even when it
+//fails. With sync the file has to be all the way on disk before we call it done.
+fn store_file(message: &mut FileMessage, out_path: Option<PathBuf>, progress: bool, sync: bool, receipt: &mut Receipt) -> Result<PathBuf> {
+    //Parts only come from fetching, which puts them together itself. Archives and links aren't
+    //files to be stored
+    if !message.is_whole() || message.content != CONTENT_FILE {
+        bail!(ErrorKind::InvalidFrame);
+    }
+    let (new_path, file) = create_output(&message.name, out_path)?;
+    let pb = if progress {
+        Some(progress_bar(message.size))
+    } else {
+        None
+    };
+
+    //A partial file looks too much like a finished one to leave lying around
+    let mut res = preallocate(&file, message.size)
+        .and_then(|_| write_content(message, &file, 0, pb.as_ref(), receipt));
+    if res.is_ok() && sync {
+        res = file.sync_all().chain_err(|| ErrorKind::WriteContent);
+    }
+    if let Err(err) = res {
+        let _ = std::fs::remove_file(&new_path);
+        return Err(err);
+    }
+    if let Some(pb) = pb {
+        pb.lock().unwrap().finish();
+    }
+    receipt.success = true;
+    return Ok(new_path);
+}
+
+//Reading from the network and writing to disk both block, so the writing is done on a thread of
+//its own. Buffers go back and forth between the two, so the reader can only get WRITE_BUFFERS
+//ahead of the disk.
+const WRITE_BUFFERS: usize = 4;
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+const PROGRESS_INTERVAL_MILLIS: u64 = 100;
+
+//Writes the content into the file starting at start. Positioned writes leave the file position
+//alone, so several parts can be written into the same file at once.
+fn write_content(message: &mut FileMessage, file: &std::fs::File, start: u64, progress: Option<&Progress>, receipt: &mut Receipt) -> Result<()> {
+    let size = message.size;
+    let file = file.try_clone()
+        .chain_err(|| ErrorKind::WriteContent)?;
+
+    let (full_sender, full_receiver) = std::sync::mpsc::sync_channel::<(Vec<u8>, usize)>(WRITE_BUFFERS);
+    let (empty_sender, empty_receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+    for _ in 0..WRITE_BUFFERS {
+        empty_sender.send(vec![0u8; WRITE_BUFFER_SIZE]).unwrap();
+    }
+    let writer = std::thread::spawn(move || -> (u64, std::io::Result<()>) {
+        use std::os::unix::fs::FileExt;
+
+        let mut written = 0;
+        for (buffer, len) in full_receiver {
+            if let Err(err) = file.write_all_at(&buffer[..len], start + written) {
+                return (written, Err(err));
+            }
+            written += len as u64;
+            //The reader might be done with buffers already
+            let _ = empty_sender.send(buffer);
+        }
+        return (written, Ok(()));
+    });
+
+    let read_res = (|| -> Result<()> {
+        //Never take more than we were promised
+        let mut content = (&mut message.file).take(size);
+        let mut total = 0;
+        loop {
+            //No buffers coming back means the writer gave up. It has the error
+            let mut buffer = match empty_receiver.recv() {
+                Ok(buffer) => buffer,
+                Err(_) => return Ok(()),
+            };
+            let mut len = 0;
+            while len < buffer.len() {
+                let read = content.read(&mut buffer[len..])
+                    .map_err(content_error)
+                    .chain_err(|| ErrorKind::ReadContent)?;
+                if read == 0 {
+                    break;
+                }
+                len += read;
+            }
+            if len == 0 {
+                break;
+            }
+            total += len as u64;
+            if let Some(pb) = progress {
+                pb.lock().unwrap().add(len as u64);
+            }
+            if full_sender.send((buffer, len)).is_err() {
+                return Ok(());
+            }
+        }
+        if total != size {
+            bail!(ErrorKind::IncompleteRead(total as usize, size as usize));
+        }
+        return read_end(message, receipt);
+    })();
+
+    //Let the writer know there's nothing more coming
+    drop(full_sender);
+    let (written, write_res) = writer.join()
+        .unwrap_or_else(|_| (0, Err(std::io::Error::new(std::io::ErrorKind::Other, "Writer thread panicked"))));
+    receipt.bytes = written;
+    write_res.chain_err(|| ErrorKind::WriteContent)?;
+    read_res?;
+    return Ok(());
+}
+
+//The checksum is in the end frame, and reading that is what checks it
+fn read_end(message: &mut FileMessage, receipt: &mut Receipt) -> Result<()> {
+    match message.file.read(&mut [0u8; 1]).map_err(content_error) {
+        Ok(0) if message.unchecked.load(std::sync::atomic::Ordering::SeqCst) => receipt.checksum = Checksum::Unchecked,
+        Ok(0) => receipt.checksum = Checksum::Verified,
+        Ok(_) => bail!(ErrorKind::InvalidFrame),
+        Err(err) => {
+            if let ErrorKind::ChecksumMismatch = *err.kind() {
+                receipt.checksum = Checksum::Mismatch;
+            }
+            return Err(err).chain_err(|| ErrorKind::ReadContent);
+        }
+    }
+    return Ok(());
+}
+
+//Pipes can't be written at a position, so the content goes out in order as it comes in
+fn write_stdout(message: &mut FileMessage, receipt: &mut Receipt) -> Result<()> {
+    //The content of a link is where it points, which would pass for the file it points to
+    if message.content == CONTENT_LINK {
+        bail!(ErrorKind::LinkToStdout(message.name.clone()));
+    }
+    let size = message.size;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let mut buffer = vec![0u8; FRAME_SIZE];
+    {
+        let mut content = (&mut message.file).take(size);
+        loop {
+            let read = content.read(&mut buffer)
+                .map_err(content_error)
+                .chain_err(|| ErrorKind::ReadContent)?;
+            if read == 0 {
+                break;
+            }
+            out.write_all(&buffer[..read])
+                .chain_err(|| ErrorKind::WriteContent)?;
+            receipt.bytes += read as u64;
+        }
+    }
+    out.flush()
+        .chain_err(|| ErrorKind::WriteContent)?;
+    if size != UNKNOWN_SIZE && receipt.bytes != size {
+        bail!(ErrorKind::IncompleteRead(receipt.bytes as usize, size as usize));
+    }
+    read_end(message, receipt)?;
+    receipt.success = true;
+    return Ok(());
+}
+
+//Unpacks an archive from a message into a new directory. The name from the message is used
+//unless we are told where to put it. Nothing is written anywhere but where it belongs
+fn store_archive(message: &mut FileMessage, out_path: Option<PathBuf>, special: bool, receipt: &mut Receipt) -> Result<(PathBuf, archive::Unpacked)> {
+    let new_path = output_path(&message.name, out_path)?;
+    match std::fs::create_dir(&new_path) {
+        Ok(_) => {},
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => bail!(ErrorKind::FileExists(new_path)),
+        Err(err) => return Err(err).chain_err(|| ErrorKind::WriteContent),
+    }
+
+    let res = {
+        let mut content = KeepError {
+            inner: &mut message.file,
+            error: None,
+        };
+        match (archive::unpack(&mut content, &new_path, special), content.error) {
+            (Ok(unpacked), _) => Ok(unpacked),
+            //Reading is what failed, tar just doesn't say why
+            (Err(_), Some(err)) => {
+                if let ErrorKind::ChecksumMismatch = *err.kind() {
+                    receipt.checksum = Checksum::Mismatch;
+                }
+                Err(err).chain_err(|| ErrorKind::ReadContent)
+            }
+            (Err(err), None) => Err(err).chain_err(|| ErrorKind::WriteContent),
+        }
+    };
+    //The archive has been read to its end already, so this is only there to mark it verified
+    let res = res.and_then(|unpacked| read_end(message, receipt).map(|_| unpacked));
+    match res {
+        Ok(unpacked) => {
+            receipt.bytes = unpacked.bytes;
+            receipt.success = true;
+            return Ok((new_path, unpacked));
+        }
+        Err(err) => {
+            //Half a directory looks too much like a whole one to leave lying around
+            let _ = archive::remove(&new_path);
+            return Err(err);
+        }
+    }
+}
+
+//Makes the link a message says to make. The name from the message is used unless we are told
+//where to put it
+fn store_link(message: &mut FileMessage, out_path: Option<PathBuf>, receipt: &mut Receipt) -> Result<(PathBuf, PathBuf)> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut target = Vec::new();
+    (&mut message.file).take(message.size).read_to_end(&mut target)
+        .map_err(content_error)
+        .chain_err(|| ErrorKind::ReadContent)?;
+    if target.len() as u64 != message.size {
+        bail!(ErrorKind::IncompleteRead(target.len(), message.size as usize));
+    }
+    read_end(message, receipt)?;
+    let target = PathBuf::from(std::ffi::OsString::from_vec(target));
+    let new_path = output_path(&message.name, out_path)?;
+    match std::os::unix::fs::symlink(&target, &new_path) {
+        Ok(_) => {},
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => bail!(ErrorKind::FileExists(new_path)),
+        Err(err) => return Err(err).chain_err(|| ErrorKind::WriteContent),
+    }
+    receipt.bytes = message.size;
+    receipt.success = true;
+    return Ok((new_path, target));
+}
+
+//Keeps the first error reading gave, for when whatever is reading doesn't pass it on as it was
+struct KeepError<R> {
+    inner: R,
+    error: Option<Error>,
+}
+
+impl<R: Read> Read for KeepError<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.inner.read(buf).map_err(|err| {
+            let copy = std::io::Error::new(err.kind(), err.to_string());
+            if self.error.is_none() {
+                self.error = Some(content_error(err));
+            }
+            return copy;
+        });
+    }
+}
+
+//Picks a name in the directory that isn't taken yet by numbering the file
+fn numbered_path(dir: &std::path::Path, name: &str, number: u32) -> PathBuf {
+    if number == 0 {
+        return dir.join(name);
+    }
+    let name = std::path::Path::new(name);
+    let stem = name.file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    return match name.extension() {
+        Some(ext) => dir.join(format!("{}-{}.{}", stem, number, ext.to_string_lossy())),
+        None => dir.join(format!("{}-{}", stem, number)),
+    };
+}
+
+//Parses sizes like 512, 10K, 20M and 1G
+//Rates are sizes per second. A rate of 0 would never send anything, so it isn't one
+pub fn parse_rate(s: &str) -> Result<u64> {
+    return match parse_size(s)? {
+        0 => Err(ErrorKind::InvalidRate(s.to_owned()).into()),
+        rate => Ok(rate),
+    };
+}
+
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, factor) = match s.chars().last().map(|x| x.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1u64 << 20),
+        Some('G') => (&s[..s.len() - 1], 1u64 << 30),
+        Some('T') => (&s[..s.len() - 1], 1u64 << 40),
+        _ => (s, 1),
+    };
+    let number = number.parse::<u64>()
+        .chain_err(|| ErrorKind::InvalidSize(s.to_owned()))?;
+    return number.checked_mul(factor)
+        .ok_or_else(|| ErrorKind::InvalidSize(s.to_owned()).into());
+}
+
+//Parses durations like 30, 30s, 10m, 2h and 1d. Plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<std::time::Duration> {
+    let s = s.trim();
+    let (number, factor) = match s.chars().last().map(|x| x.to_ascii_lowercase()) {
+        Some('s') => (&s[..s.len() - 1], 1u64),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        Some('d') => (&s[..s.len() - 1], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    let number = number.parse::<u64>()
+        .chain_err(|| ErrorKind::InvalidDuration(s.to_owned()))?;
+    return number.checked_mul(factor)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| ErrorKind::InvalidDuration(s.to_owned()).into());
+}
+
+pub const DEFAULT_PORT: u16 = 2222;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub connect: std::time::Duration,
+    //How long a read or write can go without progress before we give up on the peer
+    pub idle: std::time::Duration,
+    //How long the connection can be quiet before keepalive probes are sent
+    pub keepalive: std::time::Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        return Timeouts {
+            connect: std::time::Duration::from_secs(10),
+            idle: std::time::Duration::from_secs(60),
+            keepalive: std::time::Duration::from_secs(30),
+        };
+    }
+}
+
+impl Timeouts {
+    pub fn configure(&self, stream: &std::net::TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(self.idle))?;
+        stream.set_write_timeout(Some(self.idle))?;
+        network::set_keepalive(stream, self.keepalive)?;
+        return Ok(());
+    }
+
+    pub fn connect(&self, addr: std::net::SocketAddrV4) -> Result<std::net::TcpStream> {
+        let stream = std::net::TcpStream::connect_timeout(&std::net::SocketAddr::V4(addr), self.connect)
+            .chain_err(|| ErrorKind::ClientConnection(*addr.ip(), addr.port()))?;
+        self.configure(&stream)?;
+        return Ok(stream);
+    }
+}
+
+//One finished transfer, successful or not
+#[derive(Clone, Debug)]
+pub struct Download {
+    pub remote_addr: std::net::SocketAddr,
+    pub name: String,
+    pub size: u64,
+    pub error: Option<String>,
+}
+
+//A download in progress. It can be split over several connections, each fetching a part
+struct Transfer {
+    remote_addr: std::net::SocketAddr,
+    name: String,
+    //How the first connection split the file. The rest have to agree, or the parts won't line up
+    parts: u16,
+    range: (u64, u64),
+    done: Vec<bool>,
+    //Connections currently sending a part
+    running: u32,
+    bytes: u64,
+    error: Option<String>,
+    last_activity: std::time::Instant,
+}
+
+struct LifetimeState {
+    //Transfers in progress. They hold on to a download slot until they are done
+    transfers: std::collections::HashMap<u64, Transfer>,
+    //Transfers that are done. Their ids are turned away, since reusing one would get another
+    //download without taking a slot
+    finished: std::collections::HashSet<u64>,
+    downloads: Vec<Download>,
+    last_activity: std::time::Instant,
+    stopped: Option<&'static str>,
+}
+
+//How long a transfer can wait for its missing parts, with none of them running, before we give
+//up on it
+const PART_WAIT_SECS: u64 = 60;
+
+//Decides when a server is done. It is shared between the repositories on all interfaces, so the
+//limits count across all of them.
+pub struct Lifetime {
+    max_downloads: Option<u32>,
+    idle_timeout: Option<std::time::Duration>,
+    expires: Option<std::time::Instant>,
+    state: std::sync::Mutex<LifetimeState>,
+    //Running transfers give up when this is set
+    aborted: std::sync::atomic::AtomicBool,
+}
+
+impl Lifetime {
+    //Lives forever until told otherwise
+    pub fn new() -> Self {
+        return Lifetime {
+            max_downloads: None,
+            idle_timeout: None,
+            expires: None,
+            state: std::sync::Mutex::new(LifetimeState {
+                transfers: std::collections::HashMap::new(),
+                finished: std::collections::HashSet::new(),
+                downloads: Vec::new(),
+                last_activity: std::time::Instant::now(),
+                stopped: None,
+            }),
+            aborted: std::sync::atomic::AtomicBool::new(false),
+        };
+    }
+
+    //Only successful downloads count
+    pub fn set_max_downloads(&mut self, max_downloads: u32) {
+        self.max_downloads = Some(max_downloads);
+    }
+
+    //Stop when nobody has connected for this long. Running transfers keep us alive
+    pub fn set_idle_timeout(&mut self, idle_timeout: std::time::Duration) {
+        self.idle_timeout = Some(idle_timeout);
+    }
+
+    pub fn set_expires_in(&mut self, expires_in: std::time::Duration) {
+        self.expires = Some(std::time::Instant::now() + expires_in);
+    }
+
+    fn successful(state: &LifetimeState) -> u32 {
+        return state.downloads.iter().filter(|x| x.error.is_none()).count() as u32;
+    }
+
+    fn complete(state: &mut LifetimeState, id: u64) {
+        if let Some(transfer) = state.transfers.remove(&id) {
+            let all_done = transfer.done.iter().all(|x| *x);
+            state.finished.insert(id);
+            state.downloads.push(Download {
+                remote_addr: transfer.remote_addr,
+                name: transfer.name,
+                size: transfer.bytes,
+                error: if all_done {
+                    None
+                } else {
+                    Some(transfer.error.unwrap_or_else(|| "Not all parts were fetched".to_owned()))
+                },
+            });
+        }
+    }
+
+    //Why we stopped, if we have. Once stopped we stay that way
+    pub fn stopped(&self) -> Option<&'static str> {
+        let mut state = self.state.lock().unwrap();
+        let now = std::time::Instant::now();
+        let stale = state.transfers.iter()
+            .filter(|&(_, x)| x.running == 0 && now - x.last_activity >= std::time::Duration::from_secs(PART_WAIT_SECS))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in stale {
+            Lifetime::complete(&mut state, id);
+        }
+
+        if state.stopped.is_none() {
+            let idle = state.transfers.is_empty();
+            if self.max_downloads.map_or(false, |max| idle && Lifetime::successful(&state) >= max) {
+                state.stopped = Some("all downloads are done");
+            } else if self.expires.map_or(false, |expires| now >= expires) {
+                state.stopped = Some("the file expired");
+            } else if self.idle_timeout.map_or(false, |timeout| idle && now - state.last_activity >= timeout) {
+                state.stopped = Some("nobody connected for too long");
+            }
+        }
+        return state.stopped;
+    }
+
+    pub fn stop(&self, reason: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped.is_none() {
+            state.stopped = Some(reason);
+        }
+    }
+
+    //Stop and make the running transfers give up too, instead of waiting for them
+    pub fn abort(&self, reason: &'static str) {
+        self.stop(reason);
+        self.aborted.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    //Transfers still running
+    pub fn active(&self) -> u32 {
+        return self.state.lock().unwrap().transfers.len() as u32;
+    }
+
+    //Start sending a part of a transfer. The first part reserves a download slot for the whole
+    //transfer, and fails with Unavailable if we are stopping or the remaining downloads are
+    //already taken by running transfers.
+    fn begin(&self, transfer: u64, part: u16, parts: u16, range: (u64, u64), remote_addr: std::net::SocketAddr, name: &str) -> Result<()> {
+        if part >= parts {
+            bail!(ErrorKind::InvalidPart(part, parts));
+        }
+        if self.stopped().is_some() {
+            bail!(ErrorKind::Unavailable);
+        }
+        let mut state = self.state.lock().unwrap();
+        let now = std::time::Instant::now();
+        state.last_activity = now;
+        if state.finished.contains(&transfer) {
+            bail!(ErrorKind::TransferDone(transfer));
+        }
+        if let Some(running) = state.transfers.get_mut(&transfer) {
+            if running.parts != parts || running.range != range {
+                bail!(ErrorKind::TransferMismatch(transfer));
+            }
+            running.running += 1;
+            running.last_activity = now;
+            return Ok(());
+        }
+        if self.max_downloads.map_or(false, |max| state.transfers.len() as u32 + Lifetime::successful(&state) >= max) {
+            bail!(ErrorKind::Unavailable);
+        }
+        state.transfers.insert(transfer, Transfer {
+            remote_addr: remote_addr,
+            name: name.to_owned(),
+            parts: parts,
+            range: range,
+            done: vec![false; parts as usize],
+            running: 1,
+            bytes: 0,
+            error: None,
+            last_activity: now,
+        });
+        return Ok(());
+    }
+
+    //The same part can be fetched more than once, like when a client tries several of our
+    //addresses at once. The transfer is done when every part has been sent successfully once.
+    fn finish(&self, transfer: u64, part: u16, bytes: u64, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        let now = std::time::Instant::now();
+        state.last_activity = now;
+        let all_done = match state.transfers.get_mut(&transfer) {
+            Some(running) => {
+                running.running -= 1;
+                running.last_activity = now;
+                match (error, running.done.get_mut(part as usize)) {
+                    (None, Some(done)) => {
+                        if !*done {
+                            *done = true;
+                            running.bytes += bytes;
+                        }
+                    }
+                    //Can't happen since begin checks the part, but a panic here would poison the lock
+                    (None, None) => {},
+                    (Some(error), _) => running.error = Some(error),
+                }
+                running.done.iter().all(|x| *x)
+            }
+            None => return,
+        };
+        if all_done {
+            Lifetime::complete(&mut state, transfer);
+        }
+    }
+
+    pub fn downloads(&self) -> Vec<Download> {
+        return self.state.lock().unwrap().downloads.clone();
+    }
+}
+
+//How often listeners look up from accepting to check if they should stop
+const LIFETIME_POLL_MILLIS: u64 = 200;
+
+pub struct FileRepository {
+    files: std::collections::HashMap<u32, FileInfo>,
+    pub interface: network::Interface,
+    next_id: u32,
+    timeouts: Timeouts,
+    lifetime: std::sync::Arc<Lifetime>,
+    limit: std::sync::Arc<RateLimit>,
+    connection_rate: Rate,
+    relay_code: u64,
+    zero_copy: bool,
+}
+
+impl FileRepository {
+    pub fn new(interface: network::Interface) -> Self {
+        use std::hash::{BuildHasher, Hasher};
+
+        return FileRepository {
+            files: std::collections::HashMap::new(),
+            interface: interface,
+            next_id: 0,
+            timeouts: Timeouts::default(),
+            lifetime: std::sync::Arc::new(Lifetime::new()),
+            limit: std::sync::Arc::new(RateLimit::new(Rate::new(None))),
+            connection_rate: Rate::new(None),
+            //Hashers are seeded from the OS, so hashing nothing is as random as it gets without
+            //another crate
+            relay_code: std::collections::hash_map::RandomState::new().build_hasher().finish(),
+            zero_copy: false,
+        };
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn set_lifetime(&mut self, lifetime: std::sync::Arc<Lifetime>) {
+        self.lifetime = lifetime;
+    }
+
+    //Limit for all transfers together. Share it between the repositories on all interfaces to
+    //have it count across them
+    pub fn set_limit(&mut self, limit: std::sync::Arc<RateLimit>) {
+        self.limit = limit;
+    }
+
+    //Limit for each connection on its own
+    pub fn set_connection_rate(&mut self, rate: Rate) {
+        self.connection_rate = rate;
+    }
+
+    //Send plain files straight from the page cache where the system lets us. It's faster, but
+    //the content goes without a checksum
+    pub fn set_zero_copy(&mut self, zero_copy: bool) {
+        self.zero_copy = zero_copy;
+    }
+
+    pub fn add_file(&mut self, file: FileInfo) -> Result<ServerTransport> {
+        self.files.insert(self.next_id, file);
+        return self.interface.addr.make_transport();
+    }
+
+    //Key for reaching us through the relay, when we are waiting at one
+    pub fn relay_transport(&self) -> Result<ServerTransport> {
+        return RelayKey::new(self.interface.addr, self.relay_code).make_transport();
+    }
+
+    //Direct address of the repository, for when the key isn't needed
+    pub fn uri(&self) -> String {
+        return DirectTransport::new(self.interface.addr, DEFAULT_PORT).uri();
+    }
+
+    fn get_file(&self, index: u32) -> Result<&FileInfo> {
+        return self.files.get(&index)
+            .ok_or_else(|| ErrorKind::UnknownFile(index).into());
+    }
+
+    //Serves until the lifetime runs out. Every connection gets a thread, since the parts of a
+    //transfer come in at the same time
+    pub fn run(repo: std::sync::Arc<FileRepository>) -> Result<()> {
+        //@Expansion: Maybe don't use fixed ports
+        let listener = std::net::TcpListener::bind((repo.interface.addr, DEFAULT_PORT))
+            .chain_err(|| ErrorKind::Bind(repo.interface.addr, DEFAULT_PORT))?;
+        //Accepting can't be interrupted, so we poll to notice when it's time to stop
+        listener.set_nonblocking(true)
+            .chain_err(|| ErrorKind::ServerConnection)?;
+
+        while repo.lifetime.stopped().is_none() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(LIFETIME_POLL_MILLIS));
+                    continue;
+                }
+                Err(err) => return Err(err).chain_err(|| ErrorKind::ServerConnection),
+            };
+            let repo = repo.clone();
+            std::thread::spawn(move || {
+                //A client timing out or going away shouldn't take the server down
+                let res = stream.set_nonblocking(false)
+                    .map_err(|err| err.into())
+                    .and_then(|_| repo.timeouts.configure(&stream))
+                    .and_then(|_| repo.serve(stream));
+                if let Err(err) = res {
+                    print_err(err);
+                }
+            });
+        }
+
+        //Let whoever is still waiting in the backlog know why they are getting hung up on
+        while let Ok((mut stream, _)) = listener.accept() {
+            let err = ErrorKind::Unavailable.into();
+            let _ = stream.set_nonblocking(false)
+                .and_then(|_| write_error(&mut stream, ERROR_UNAVAILABLE, &err));
+        }
+        return Ok(());
+    }
+
+    //Wait for clients at a relay instead of listening. Every client uses up a connection to the
+    //relay, so we make a new one as soon as someone shows up. Losing the relay only takes us off
+    //it until it is back.
+    pub fn run_relay(repo: std::sync::Arc<FileRepository>, relay: std::net::SocketAddrV4) -> Result<()> {
+        let code = relay_code(repo.relay_code);
+        let mut backoff = INITIAL_BACKOFF_SECS;
+        while repo.lifetime.stopped().is_none() {
+            let stream = match relay::connect(relay, relay::Role::Listen, &code, &repo.timeouts) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    print_err(Error::with_chain(err, ErrorKind::RelayConnection(std::net::SocketAddr::V4(relay))));
+                    println!("{} the relay in {}s", Yellow.paint("Retrying"), backoff);
+                    std::thread::sleep(std::time::Duration::from_secs(backoff));
+                    backoff = std::cmp::min(backoff * 2, MAX_BACKOFF_SECS);
+                    continue;
+                }
+            };
+            backoff = INITIAL_BACKOFF_SECS;
+            let repo = repo.clone();
+            std::thread::spawn(move || {
+                //A client going away shouldn't take us off the relay
+                if let Err(err) = repo.serve(stream) {
+                    print_err(err);
+                }
+            });
+        }
+        return Ok(());
+    }
+
+    fn serve(&self, mut stream: std::net::TcpStream) -> Result<()> {
+        let remote_addr = stream.peer_addr()?;
+        let request = Request::read(&mut stream)
+            .chain_err(|| ErrorKind::SendFile(remote_addr))?;
+        let file = match self.get_file(request.file) {
+            Ok(file) => file,
+            Err(err) => {
+                let _ = write_error(&mut stream, ERROR_UNKNOWN_FILE, &err);
+                return Err(err).chain_err(|| ErrorKind::SendFile(remote_addr));
+            }
+        };
+        let range = if file.is_whole_only() && request.range != ByteRange::whole() {
+            Err(ErrorKind::NoRanges.into())
+        } else {
+            request.range.resolve(file.len)
+        };
+        let (start, len) = match range {
+            Ok(range) => range,
+            Err(err) => {
+                let _ = write_error(&mut stream, ERROR_INVALID_RANGE, &err);
+                return Err(err).chain_err(|| ErrorKind::SendFile(remote_addr));
+            }
+        };
+        let parts = if file.is_whole_only() {
+            1
+        } else {
+            negotiate_parts(len, request.parts)
+        };
+        if let Err(err) = self.lifetime.begin(request.transfer, request.part, parts, (start, len), remote_addr, &file.path.to_string_lossy()) {
+            if let ErrorKind::Unavailable = *err.kind() {
+                //Someone else got the last download
+                info!("Turned away {}", remote_addr);
+                let _ = write_error(&mut stream, ERROR_UNAVAILABLE, &err);
+                return Ok(());
+            }
+            let _ = write_error(&mut stream, ERROR_OTHER, &err);
+            return Err(err).chain_err(|| ErrorKind::SendFile(remote_addr));
+        }
+        let (offset, size) = split_part(len, request.part, parts);
+        let mut limits = Limits::new();
+        limits.add(std::sync::Arc::new(RateLimit::new(self.connection_rate.clone())));
+        limits.add(self.limit.clone());
+        let res = if file.is_dir() {
+            send_archive(&mut stream, file, request.codecs, Some(&self.lifetime.aborted), limits)
+        } else if let archive::Kind::Symlink(ref target) = file.kind {
+            send_link(&mut stream, file, target, limits)
+        } else {
+            send_file(&mut stream, file, start + offset, size, parts, request.codecs, self.zero_copy, Some(&self.lifetime.aborted), limits)
+                .map(|_| size)
+        };
+        if let Err(ref err) = res {
+            let code = if self.lifetime.aborted.load(std::sync::atomic::Ordering::SeqCst) {
+                ERROR_ABORTED
+            } else {
+                ERROR_OTHER
+            };
+            //The connection might be what failed, in which case nobody hears about it
+            let _ = write_error(&mut stream, code, err);
+        }
+        let sent = *res.as_ref().unwrap_or(&0);
+        let res = res.chain_err(|| ErrorKind::SendFile(remote_addr))
+            .and_then(|_| self.wait_receipt(&mut stream, remote_addr, request.part, parts));
+        self.lifetime.finish(request.transfer,
+                             request.part,
+                             sent,
+                             //The root cause says the most about what went wrong
+                             res.as_ref().err().and_then(|x| x.iter().last()).map(|x| x.to_string()));
+        return res;
+    }
+
+    //Only the client knows if it actually got the file
+    fn wait_receipt(&self, stream: &mut std::net::TcpStream, remote_addr: std::net::SocketAddr, part: u16, parts: u16) -> Result<()> {
+        let receipt = Receipt::read(stream)
+            .chain_err(|| ErrorKind::Receipt)
+            .chain_err(|| ErrorKind::SendFile(remote_addr))?;
+        if !receipt.success {
+            return Err(Error::from(ErrorKind::NotDelivered(receipt.bytes, receipt.checksum)))
+                .chain_err(|| ErrorKind::SendFile(remote_addr));
+        }
+        if parts > 1 {
+            println!("{} part {} of {} to {} ({})",
+                     Green.paint("Delivered"),
+                     part + 1,
+                     parts,
+                     Yellow.paint(remote_addr.ip().to_string()),
+                     receipt.checksum);
+        } else {
+            println!("{} to {} ({})",
+                     Green.paint("Delivered"),
+                     Yellow.paint(remote_addr.ip().to_string()),
+                     receipt.checksum);
+        }
+        return Ok(());
+    }
+}
+
+//The other way around from the FileRepository. We listen and the sender connects to us, for when
+//the sender can't be reached.
+pub struct FileReceiver {
+    pub interface: network::Interface,
+    timeouts: Timeouts,
+}
+
+impl FileReceiver {
+    pub fn new(interface: network::Interface) -> Self {
+        return FileReceiver {
+            interface: interface,
+            timeouts: Timeouts::default(),
+        };
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn make_transport(&self) -> Result<ServerTransport> {
+        return self.interface.addr.make_transport();
+    }
+
+    pub fn uri(&self) -> String {
+        return DirectTransport::new(self.interface.addr, DEFAULT_PORT).uri();
+    }
+
+    //Waits for a single file to be pushed to us
+    pub fn run(&self, out_path: Option<PathBuf>) -> Result<(std::net::SocketAddr, PathBuf)> {
+        let listener = std::net::TcpListener::bind((self.interface.addr, DEFAULT_PORT))
+            .chain_err(|| ErrorKind::Bind(self.interface.addr, DEFAULT_PORT))?;
+
+        let (stream, remote_addr) = listener.accept()
+            .chain_err(|| ErrorKind::ServerConnection)?;
+        let mut receipt = Receipt::new();
+        let res = self.timeouts.configure(&stream)
+            .and_then(|_| Ok(stream.try_clone()?))
+            .and_then(|back| {
+                let res = FileMessage::read(stream)
+                    .and_then(|mut message| store_file(&mut message, out_path, true, false, &mut receipt));
+                answer_push(back, &res, &receipt);
+                return res;
+            });
+        let path = res.chain_err(|| ErrorKind::ReceiveFile(remote_addr))?;
+        return Ok((remote_addr, path));
+    }
+}
+
+//Waits for the receiver to say something. While we are still sending, it can take as long as the
+//sending does, so only the wait after we are done is held to the idle timeout
+fn wait_answer(stream: &mut std::net::TcpStream, sent: &std::sync::atomic::AtomicBool) -> Result<()> {
+    loop {
+        match stream.peek(&mut [0u8; 1]) {
+            Ok(_) => return Ok(()),
+            Err(ref err) if (err.kind() == std::io::ErrorKind::WouldBlock || err.kind() == std::io::ErrorKind::TimedOut) &&
+                !sent.load(std::sync::atomic::Ordering::SeqCst) => {},
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+//Lets whoever pushed to us know how it went. A stored file gets a receipt, anything else the
+//error. The pusher stops sending once it hears about an error, so the rest of what it sent is
+//read until it hangs up, or the error could get lost when we do.
+fn answer_push(mut back: std::net::TcpStream, res: &Result<PathBuf>, receipt: &Receipt) {
+    let sent = match *res {
+        Ok(_) => receipt.write(&mut back),
+        Err(ref err) => write_error(&mut back, ERROR_OTHER, err)
+            .and_then(|_| back.shutdown(std::net::Shutdown::Write))
+            .and_then(|_| std::io::copy(&mut back, &mut std::io::sink()).map(|_| ())),
+    };
+    if let Err(err) = sent {
+        info!("Failed answering the push: {}", err);
+    }
+}
+
+//An upload waiting to be let into the inbox
+pub struct Upload<'a> {
+    pub name: &'a str,
+    pub size: u64,
+    pub remote_addr: std::net::SocketAddr,
+}
+
+pub type Approval = Box<dyn Fn(&Upload) -> bool + Send + Sync>;
+
+//A shared drop box many clients can push files into. Each upload is checked against the limits
+//before anything is written.
+pub struct FileInbox {
+    dir: PathBuf,
+    max_file_size: Option<u64>,
+    quota: Option<u64>,
+    //Bytes taken by stored and in flight uploads
+    used: std::sync::Mutex<u64>,
+    approval: Option<Approval>,
+    timeouts: Timeouts,
+}
+
+impl FileInbox {
+    pub fn new(dir: PathBuf, max_file_size: Option<u64>, quota: Option<u64>) -> Self {
+        return FileInbox {
+            dir: dir,
+            max_file_size: max_file_size,
+            quota: quota,
+            used: std::sync::Mutex::new(0),
+            approval: None,
+            timeouts: Timeouts::default(),
+        };
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    //Files left from earlier runs count against the quota too
+    pub fn count_existing(&mut self) -> Result<()> {
+        let mut used = 0;
+        for entry in std::fs::read_dir(&self.dir).chain_err(|| ErrorKind::InboxUsage(self.dir.clone()))? {
+            let metadata = entry.and_then(|x| x.metadata())
+                .chain_err(|| ErrorKind::InboxUsage(self.dir.clone()))?;
+            if metadata.is_file() {
+                used += metadata.len();
+            }
+        }
+        self.used = std::sync::Mutex::new(used);
+        return Ok(());
+    }
+
+    //Ask before letting an upload in. Called from the connection threads, so it has to do its
+    //own locking if it talks to the user
+    pub fn set_approval(&mut self, approval: Approval) {
+        self.approval = Some(approval);
+    }
+
+    pub fn run(inbox: std::sync::Arc<FileInbox>, addr: std::net::Ipv4Addr) -> Result<()> {
+        let listener = std::net::TcpListener::bind((addr, DEFAULT_PORT))
+            .chain_err(|| ErrorKind::Bind(addr, DEFAULT_PORT))?;
+
+        loop {
+            let (stream, remote_addr) = listener.accept()
+                .chain_err(|| ErrorKind::ServerConnection)?;
+            let inbox = inbox.clone();
+            std::thread::spawn(move || {
+                match inbox.receive(stream, remote_addr) {
+                    Ok(path) => println!("{} {} from {}",
+                                         Green.paint("Received"),
+                                         path.to_string_lossy(),
+                                         Yellow.paint(remote_addr.to_string())),
+                    Err(err) => print_err(Error::with_chain(err, ErrorKind::ReceiveFile(remote_addr))),
+                }
+            });
+        }
+    }
+
+    fn receive(&self, stream: std::net::TcpStream, remote_addr: std::net::SocketAddr) -> Result<PathBuf> {
+        self.timeouts.configure(&stream)?;
+        let back = stream.try_clone()?;
+        let mut receipt = Receipt::new();
+        let res = FileMessage::read(stream)
+            .and_then(|mut message| self.accept(&mut message, remote_addr, &mut receipt));
+        answer_push(back, &res, &receipt);
+        return res;
+    }
+
+    fn accept(&self, message: &mut FileMessage, remote_addr: std::net::SocketAddr, receipt: &mut Receipt) -> Result<PathBuf> {
+        let size = message.size as u64;
+
+        //Only the name is used. The sender doesn't get to pick the directory
+        let name = match std::path::Path::new(&message.name).file_name().and_then(|x| x.to_str()) {
+            Some(name) => name.to_owned(),
+            None => bail!(ErrorKind::PathConversion),
+        };
+
+        if let Some(max) = self.max_file_size {
+            if size > max {
+                bail!(ErrorKind::UploadTooLarge(name, size, max));
+            }
+        }
+
+        //Reserve the space up front so parallel uploads can't overrun the quota together. The size
+        //comes from the sender, so it can't be trusted not to overflow. Content of unknown size
+        //only fits when there is no quota, and is counted once it is known.
+        let reserved = if size == UNKNOWN_SIZE { 0 } else { size };
+        {
+            let mut used = self.used.lock().unwrap();
+            if let Some(quota) = self.quota {
+                match used.checked_add(size) {
+                    Some(total) if total <= quota => {},
+                    _ => bail!(ErrorKind::QuotaExceeded(name, size, quota.saturating_sub(*used))),
+                }
+            }
+            *used = used.saturating_add(reserved);
+        }
+
+        let res = self.store(message, &name, remote_addr, receipt);
+        let mut used = self.used.lock().unwrap();
+        if res.is_err() {
+            *used -= reserved;
+        } else if size == UNKNOWN_SIZE {
+            *used = used.saturating_add(receipt.bytes);
+        }
+        return res;
+    }
+
+    fn store(&self, message: &mut FileMessage, name: &str, remote_addr: std::net::SocketAddr, receipt: &mut Receipt) -> Result<PathBuf> {
+        if let Some(ref approval) = self.approval {
+            let upload = Upload {
+                name: name,
+                size: message.size as u64,
+                remote_addr: remote_addr,
+            };
+            if !approval(&upload) {
+                bail!(ErrorKind::UploadDenied(name.to_owned()));
+            }
+        }
+
+        let mut number = 0;
+        loop {
+            let path = numbered_path(&self.dir, name, number);
+            match store_file(message, Some(path.clone()), false, false, receipt) {
+                Ok(path) => return Ok(path),
+                Err(Error(ErrorKind::FileExists(_), _)) => number += 1,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+pub struct FileClient {
+    relay: Option<std::net::SocketAddrV4>,
+    relay_code: Option<u64>,
+    timeouts: Timeouts,
+    retries: u32,
+    sync: bool,
+    streams: u16,
+    range: ByteRange,
+    stdout: bool,
+    compression: bool,
+    //Make the devices and FIFOs in archives
+    special: bool,
+    limit: std::sync::Arc<RateLimit>,
+    zero_copy: bool,
+}
+
+//Backoff between connection attempts. Doubles every time up to the max
+const INITIAL_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 30;
+
+impl FileClient{
+    pub fn new() -> Self {
+        return FileClient {
+            relay: None,
+            relay_code: None,
+            timeouts: Timeouts::default(),
+            retries: 3,
+            sync: false,
+            streams: 1,
+            range: ByteRange::whole(),
+            stdout: false,
+            compression: true,
+            special: false,
+            limit: std::sync::Arc::new(RateLimit::new(Rate::new(None))),
+            zero_copy: false,
+        }
+    }
+
+    //Go through the relay when the server can't be reached directly
+    pub fn set_relay(&mut self, relay: std::net::SocketAddrV4) {
+        self.relay = Some(relay);
+    }
+
+    //Code from the key to meet the server under at the relay
+    pub fn set_relay_code(&mut self, code: u64) {
+        self.relay_code = Some(code);
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    //How many times to try again after the first connection attempt fails
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    //Make sure downloads are all the way on disk before calling them done
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    //How many connections to fetch over at once. The server might give us fewer, like when the
+    //file is small
+    pub fn set_streams(&mut self, streams: u16) {
+        self.streams = std::cmp::max(streams, 1);
+    }
+
+    //Only fetch part of the file
+    pub fn set_range(&mut self, range: ByteRange) {
+        self.range = range;
+    }
+
+    //Write downloads to stdout instead of a file. Anything else we have to say goes to stderr then
+    pub fn set_stdout(&mut self, stdout: bool) {
+        self.stdout = stdout;
+    }
+
+    //Let the server compress the content when it thinks it's worth it
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    pub fn set_special(&mut self, special: bool) {
+        self.special = special;
+    }
+
+    //Push without copying, like FileRepository::set_zero_copy
+    pub fn set_zero_copy(&mut self, zero_copy: bool) {
+        self.zero_copy = zero_copy;
+    }
+
+    //Limit for everything we send and receive, across all connections
+    pub fn set_limit(&mut self, limit: std::sync::Arc<RateLimit>) {
+        self.limit = limit;
+    }
+
+    fn limits(&self) -> Limits {
+        let mut limits = Limits::new();
+        limits.add(self.limit.clone());
+        return limits;
+    }
+
+    fn say(&self, message: std::fmt::Arguments) {
+        if self.stdout {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    //With a request the connection only counts once the server has started answering it.
+    //Returns the address we got through to, or None if it was through the relay
+    fn connect(&self, candidates: &[std::net::SocketAddrV4], request: Option<&Request>) -> Result<(std::net::TcpStream, Option<std::net::SocketAddrV4>)> {
+        let attempts = self.retries + 1;
+        let mut backoff = INITIAL_BACKOFF_SECS;
+        let mut attempt = 1;
+        loop {
+            let err = match self.try_connect(candidates, request) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => Error::with_chain(err, ErrorKind::ConnectAttempt(attempt, attempts)),
+            };
+            if attempt == attempts {
+                return Err(err);
+            }
+            print_err(err);
+            self.say(format_args!("{} in {}s", Yellow.paint("Retrying"), backoff));
+            std::thread::sleep(std::time::Duration::from_secs(backoff));
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF_SECS);
+            attempt += 1;
+        }
+    }
+
+    fn try_connect(&self, candidates: &[std::net::SocketAddrV4], request: Option<&Request>) -> Result<(std::net::TcpStream, Option<std::net::SocketAddrV4>)> {
+        let err = match self.race(candidates, request) {
+            Ok((stream, addr)) => {
+                if candidates.len() > 1 {
+                    self.say(format_args!("{} {}", Green.paint("Connected through"), Yellow.paint(addr.to_string())));
+                }
+                return Ok((stream, Some(addr)));
+            }
+            Err(err) => err,
+        };
+        let relay = match self.relay {
+            Some(relay) => relay,
+            None => return Err(err),
+        };
+        if self.relay_code.is_none() {
+            return Err(Error::with_chain(err, ErrorKind::NoRelayCode));
+        }
+        self.say(format_args!("{} {} directly, trying relay {}",
+                              Red.paint("Failed reaching"),
+                              FileClient::describe(candidates),
+                              Yellow.paint(relay.to_string())));
+        return self.connect_relay(request).map(|stream| (stream, None));
+    }
+
+    //Only called once we know there is a relay and a code to meet the server under
+    fn connect_relay(&self, request: Option<&Request>) -> Result<std::net::TcpStream> {
+        let code = relay_code(self.relay_code.unwrap());
+        let mut stream = relay::connect(self.relay.unwrap(), relay::Role::Join, &code, &self.timeouts)?;
+        if let Some(request) = request {
+            request.write(&mut stream)?;
+        }
+        return Ok(stream);
+    }
+
+    //Tries all candidates at once and keeps the first one to start sending the file. The rest are
+    //hung up on as they come in. If none make it, the error of the last one to fail is returned.
+    fn race(&self, candidates: &[std::net::SocketAddrV4], request: Option<&Request>) -> Result<(std::net::TcpStream, std::net::SocketAddrV4)> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for addr in candidates.iter().cloned() {
+            let sender = sender.clone();
+            let timeouts = self.timeouts;
+            let request = request.cloned();
+            std::thread::spawn(move || {
+                let res = timeouts.connect(addr).and_then(|mut stream| {
+                    if let Some(request) = request {
+                        request.write(&mut stream)?;
+                        //A candidate that can't serve us answers with an error frame, and mustn't
+                        //win over one that can
+                        let mut frame = [0u8; 1];
+                        if stream.peek(&mut frame)? == 0 {
+                            bail!(ErrorKind::IncompleteRead(0, 1));
+                        }
+                        match frame[0] {
+                            FRAME_FILE => {},
+                            FRAME_ERROR => {
+                                stream.read_u8()?;
+                                let err = RemoteError::read(&mut stream)?;
+                                bail!(ErrorKind::Remote(err.code, err.message));
+                            }
+                            _ => bail!(ErrorKind::InvalidFrame),
+                        }
+                    }
+                    return Ok(stream);
+                });
+                let _ = sender.send((addr, res));
+            });
+        }
+        drop(sender);
+
+        let mut last_err = None;
+        for (addr, res) in receiver {
+            match res {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(err) => {
+                    info!("Candidate {} failed: {}", addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        return Err(last_err.unwrap_or_else(|| ErrorKind::InvalidAddressList.into()));
+    }
+
+    fn describe(candidates: &[std::net::SocketAddrV4]) -> String {
+        return candidates.iter()
+            .map(|x| x.ip().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+    }
+
+    pub fn get_file(&self, candidates: &[std::net::SocketAddrV4], out_path: Option<std::path::PathBuf>) -> Result<()> {
+        use std::hash::{BuildHasher, Hasher};
+
+        self.say(format_args!("{} from ip {}",
+                              Green.paint("Downloading"),
+                              Yellow.paint(FileClient::describe(candidates))));
+        //Only has to tell our connections apart from everyone else's, so the random seed of a
+        //hasher will do
+        let transfer = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        let mut request = Request {
+            file: 0,
+            transfer: transfer,
+            part: 0,
+            //Parts have to be written in order to a pipe, so there is no point in more than one
+            parts: if self.stdout { 1 } else { self.streams },
+            range: self.range,
+            codecs: if self.compression { Codec::supported() } else { 0 },
+        };
+        //The server answers right away, so the first to say something is the one to use
+        let (stream, addr) = self.connect(candidates, Some(&request))?;
+        let mut first = FileMessage::read(Throttled::new(stream.try_clone()?, self.limits()))
+            .chain_err(|| ErrorKind::Fetch)?;
+        if first.codec != Codec::None {
+            self.say(format_args!("{} with {}", Green.paint("Compressed"), first.codec));
+        }
+        if self.stdout {
+            let mut back = stream;
+            let mut receipt = Receipt::new();
+            let res = write_stdout(&mut first, &mut receipt);
+            FileClient::send_receipts(std::slice::from_mut(&mut back), &[receipt]);
+            return res;
+        }
+        if first.content == CONTENT_LINK {
+            let mut back = stream;
+            let mut receipt = Receipt::new();
+            let res = store_link(&mut first, out_path, &mut receipt);
+            FileClient::send_receipts(std::slice::from_mut(&mut back), &[receipt]);
+            let (path, target) = res?;
+            self.say(format_args!("{} {} to {}",
+                                  Green.paint("Linked"),
+                                  Yellow.paint(path.to_string_lossy()),
+                                  target.to_string_lossy()));
+            return Ok(());
+        }
+        if first.content == CONTENT_ARCHIVE {
+            let mut back = stream;
+            let mut receipt = Receipt::new();
+            let res = store_archive(&mut first, out_path, self.special, &mut receipt);
+            FileClient::send_receipts(std::slice::from_mut(&mut back), &[receipt]);
+            let (path, unpacked) = res?;
+            self.say(format_args!("{} {} files ({} bytes) into {}",
+                                  Green.paint("Unpacked"),
+                                  unpacked.files,
+                                  unpacked.bytes,
+                                  Yellow.paint(path.to_string_lossy())));
+            return Ok(());
+        }
+        let mut parts = vec![(stream, first)];
+
+        //The rest of the parts go the same way the first one did
+        for part in 1..parts[0].1.parts {
+            request.part = part;
+            let stream = match addr {
+                Some(addr) => self.connect(&[addr], Some(&request))?.0,
+                None => self.connect_relay(Some(&request))?,
+            };
+            let message = FileMessage::read(Throttled::new(stream.try_clone()?, self.limits()))
+                .chain_err(|| ErrorKind::Fetch)?;
+            parts.push((stream, message));
+        }
+        if parts.len() > 1 {
+            self.say(format_args!("{} over {} connections", Green.paint("Fetching"), parts.len()));
+        }
+        FileClient::store_parts(parts, out_path, self.sync)?;
+        return Ok(());
+    }
+
+    //Writes all the parts into place at once. The receipts only go out once the whole file is
+    //stored, since a part is no use on its own
+    fn store_parts(parts: Vec<(std::net::TcpStream, FileMessage<'static>)>, out_path: Option<PathBuf>, sync: bool) -> Result<PathBuf> {
+        let (name, total, count, start) = {
+            let first = &parts[0].1;
+            (first.name.clone(), first.total, first.parts, first.offset)
+        };
+        let (mut backs, messages): (Vec<_>, Vec<_>) = parts.into_iter().unzip();
+        let mut receipts = vec![Receipt::new(); messages.len()];
+
+        //Every part has to agree on the file, and pick up where the one before it left off
+        let mut end = start;
+        let mut valid = true;
+        for message in messages.iter() {
+            valid &= message.name == name && message.total == total && message.parts == count &&
+                message.offset == end;
+            end = message.offset + message.size;
+        }
+        let size = end - start;
+        if !valid {
+            FileClient::send_receipts(&mut backs, &receipts);
+            bail!(ErrorKind::InvalidFrame);
+        }
+
+        let (path, file) = match create_output(&name, out_path) {
+            Ok(output) => output,
+            Err(err) => {
+                FileClient::send_receipts(&mut backs, &receipts);
+                return Err(err);
+            }
+        };
+        let file = std::sync::Arc::new(file);
+        let pb = std::sync::Arc::new(progress_bar(size));
+        let mut res = preallocate(&file, size);
+        if res.is_ok() {
+            let writers = messages.into_iter().map(|mut message| {
+                let file = file.clone();
+                let pb = pb.clone();
+                return std::thread::spawn(move || {
+                    let mut receipt = Receipt::new();
+                    //Only the range we asked for ends up in the file
+                    let position = message.offset - start;
+                    let res = write_content(&mut message, &file, position, Some(&*pb), &mut receipt);
+                    return (receipt, res);
+                });
+            }).collect::<Vec<_>>();
+            for (i, writer) in writers.into_iter().enumerate() {
+                let (receipt, part_res) = writer.join()
+                    .unwrap_or_else(|_| (Receipt::new(), Err(ErrorKind::WriteContent.into())));
+                receipts[i] = receipt;
+                if res.is_ok() {
+                    res = part_res;
+                }
+            }
+        }
+        if res.is_ok() && sync {
+            res = file.sync_all().chain_err(|| ErrorKind::WriteContent);
+        }
+        //A partial file looks too much like a finished one to leave lying around
+        match res {
+            Ok(_) => {
+                pb.lock().unwrap().finish();
+                for receipt in receipts.iter_mut() {
+                    receipt.success = true;
+                }
+            }
+            Err(_) => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        FileClient::send_receipts(&mut backs, &receipts);
+        res?;
+        return Ok(path);
+    }
+
+    fn send_receipts(backs: &mut [std::net::TcpStream], receipts: &[Receipt]) {
+        //The file is stored or not either way, so the server not hearing about it doesn't change
+        //anything for us
+        for (back, receipt) in backs.iter_mut().zip(receipts) {
+            if let Err(err) = receipt.write(back) {
+                info!("Failed sending the receipt: {}", err);
+            }
+        }
+    }
+
+    pub fn push_file(&self, candidates: &[std::net::SocketAddrV4], file: &FileInfo) -> Result<()> {
+        println!("{} to ip {}",
+                 Green.paint("Uploading"),
+                 Yellow.paint(FileClient::describe(candidates)));
+        if file.is_dir() {
+            bail!(ErrorKind::DirectoryUpload(file.path.clone()));
+        }
+        let (mut stream, addr) = self.connect(candidates, None)?;
+        //The peer might be gone by the time sending fails, so it can't be asked for its address
+        //then. Going through the relay means there were candidates to go by.
+        let remote_addr = std::net::SocketAddr::V4(addr.unwrap_or(candidates[0]));
+
+        //The receiver can turn us down while we are still sending, so its answer is listened for
+        //from the start. Hearing it stops the sending.
+        let abort = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let sent = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let answer = {
+            let mut back = stream.try_clone()?;
+            let abort = abort.clone();
+            let sent = sent.clone();
+            std::thread::spawn(move || -> Result<Receipt> {
+                let res = wait_answer(&mut back, &sent).and_then(|_| Receipt::read(&mut back));
+                abort.store(true, std::sync::atomic::Ordering::SeqCst);
+                return res;
+            })
+        };
+        let res = send_file(&mut stream, file, 0, file.len, 1, 0, self.zero_copy, Some(&abort), self.limits());
+        sent.store(true, std::sync::atomic::Ordering::SeqCst);
+        if let Err(err) = res {
+            //Aborting means the receiver had something to say, which says more than our side of it
+            if !abort.load(std::sync::atomic::Ordering::SeqCst) {
+                let _ = write_error(&mut stream, ERROR_OTHER, &err);
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+                let _ = answer.join();
+                return Err(err).chain_err(|| ErrorKind::SendFile(remote_addr));
+            }
+        }
+        let receipt = answer.join()
+            .unwrap_or_else(|_| bail!("Answer thread panicked"))
+            .chain_err(|| ErrorKind::Receipt)
+            .chain_err(|| ErrorKind::SendFile(remote_addr))?;
+        if !receipt.success {
+            return Err(Error::from(ErrorKind::NotDelivered(receipt.bytes, receipt.checksum)))
+                .chain_err(|| ErrorKind::SendFile(remote_addr));
+        }
+        println!("{} to {} ({})",
+                 Green.paint("Delivered"),
+                 Yellow.paint(remote_addr.ip().to_string()),
+                 receipt.checksum);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary() -> Dictionary<'static> {
+        static DATA: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"), "/words.bin"));
+        static OFFSETS: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"), "/offsets.bin"));
+        return Dictionary::new(DATA, OFFSETS, (OFFSETS.len() / 4 - 1) as u32);
+    }
+
+    //Small xorshift so the property tests are repeatable without pulling in a crate for it
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            return self.0;
+        }
+
+        fn payload(&mut self, bits: u32) -> Vec<u8> {
+            let mut payload = max_payload(bits);
+            for byte in payload.iter_mut() {
+                *byte &= self.next() as u8;
+            }
+            return payload;
+        }
+    }
+
+    fn round_trip(presenter: &TransportPresenter, state: Vec<u8>, bits: u32) -> Vec<u8> {
+        let len = state.len();
+        let key = presenter.present(&ServerTransport::new(state, bits)).unwrap();
+        let client = presenter.present_inv(key).unwrap();
+        return fixed_payload(PartialTransport::state(&client), len).unwrap();
+    }
+
+    #[test]
+    fn random_payloads_round_trip() {
+        let presenter = TransportPresenter::new(dictionary());
+        let mut random = Random(0x9E3779B97F4A7C15);
+        for bits in 1..200 {
+            for _ in 0..20 {
+                let state = random.payload(bits);
+                assert_eq!(round_trip(&presenter, state.clone(), bits), state, "{} bits", bits);
+            }
+        }
+    }
+
+    #[test]
+    fn edge_payloads_round_trip() {
+        let presenter = TransportPresenter::new(dictionary());
+        for bits in 1..200 {
+            let zero = vec![0u8; ((bits + 7) / 8) as usize];
+            assert_eq!(round_trip(&presenter, zero.clone(), bits), zero, "zero with {} bits", bits);
+            let max = max_payload(bits);
+            assert_eq!(round_trip(&presenter, max.clone(), bits), max, "max with {} bits", bits);
+        }
+    }
+
+    #[test]
+    fn word_count_depends_on_bits_only() {
+        let presenter = TransportPresenter::new(dictionary());
+        for bits in 1..200 {
+            let words = |state: Vec<u8>| presenter.present(&ServerTransport::new(state, bits)).unwrap().split(' ').count();
+            assert_eq!(words(vec![0u8; ((bits + 7) / 8) as usize]), words(max_payload(bits)));
+        }
+    }
+
+    #[test]
+    fn address_port_id_and_secret_round_trip() {
+        let presenter = TransportPresenter::new(dictionary());
+        let mut random = Random(0x2545F4914F6CDD1D);
+        let mut addrs = vec![
+            std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(0, 0, 0, 0), 0),
+            std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(255, 255, 255, 255), std::u16::MAX),
+            std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(127, 0, 0, 1), DEFAULT_PORT),
+        ];
+        for _ in 0..50 {
+            addrs.push(std::net::SocketAddrV4::new(std::net::Ipv4Addr::from(random.next() as u32), random.next() as u16));
+        }
+        let ids = [0, 1, std::u32::MAX, random.next() as u32];
+        let secrets = [0, std::u64::MAX, random.next(), random.next()];
+
+        for addr in &addrs {
+            let transport = addr.make_transport().unwrap();
+            let key = presenter.present(&transport).unwrap();
+            assert_eq!(std::net::SocketAddrV4::from_transport(presenter.present_inv(key).unwrap()).unwrap(), *addr);
+
+            for id in &ids {
+                for secret in &secrets {
+                    let mut state = Transport::state(&transport).to_vec();
+                    state.write_u32::<BigEndian>(*id).unwrap();
+                    state.write_u64::<BigEndian>(*secret).unwrap();
+                    let state = round_trip(&presenter, state, 48 + 32 + 64);
+
+                    let addr_back = std::net::SocketAddrV4::from_transport(ServerTransport::new(state[..6].to_vec(), 48)).unwrap();
+                    assert_eq!(addr_back, *addr);
+                    assert_eq!(BigEndian::read_u32(&state[6..10]), *id);
+                    assert_eq!(BigEndian::read_u64(&state[10..]), *secret);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn relay_keys_round_trip() {
+        let presenter = TransportPresenter::new(dictionary());
+        let mut random = Random(0x5DEECE66D);
+        let addrs = [std::net::Ipv4Addr::new(0, 0, 0, 0), std::net::Ipv4Addr::new(255, 255, 255, 255), std::net::Ipv4Addr::new(10, 0, 0, 2)];
+        let codes = [0, std::u64::MAX, random.next(), random.next()];
+        for addr in &addrs {
+            for code in &codes {
+                let key = presenter.present(&RelayKey::new(*addr, *code).make_transport().unwrap()).unwrap();
+                assert_eq!(resolve_relay_code(&presenter, &key), Some(*code));
+                assert_eq!(resolve_key(&presenter, &key, false).unwrap(), vec![std::net::SocketAddrV4::new(*addr, DEFAULT_PORT)]);
+            }
+        }
+
+        //Plain keys have no code, even when they are as long as a relay key
+        let list = AddressList::new(vec![std::net::Ipv4Addr::new(255, 1, 2, 3); 3]).make_transport().unwrap();
+        assert_eq!(resolve_relay_code(&presenter, &presenter.present(&list).unwrap()), None);
+        let single = std::net::Ipv4Addr::new(255, 1, 2, 3).make_transport().unwrap();
+        assert_eq!(resolve_relay_code(&presenter, &presenter.present(&single).unwrap()), None);
+    }
+
+    #[test]
+    fn parts_have_to_match_their_transfer() {
+        let lifetime = Lifetime::new();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        lifetime.begin(1, 0, 1, (0, 10), addr, "file").unwrap();
+        match lifetime.begin(1, 5, 8, (0, 10), addr, "file") {
+            Err(Error(ErrorKind::TransferMismatch(1), _)) => {},
+            res => panic!("Unexpected result: {:?}", res.map_err(|x| x.to_string())),
+        }
+        match lifetime.begin(1, 0, 1, (5, 5), addr, "file") {
+            Err(Error(ErrorKind::TransferMismatch(1), _)) => {},
+            res => panic!("Unexpected result: {:?}", res.map_err(|x| x.to_string())),
+        }
+        match lifetime.begin(2, 1, 1, (0, 10), addr, "file") {
+            Err(Error(ErrorKind::InvalidPart(1, 1), _)) => {},
+            res => panic!("Unexpected result: {:?}", res.map_err(|x| x.to_string())),
+        }
+
+        lifetime.finish(1, 0, 10, None);
+        let downloads = lifetime.downloads();
+        assert_eq!(downloads.len(), 1);
+        assert!(downloads[0].error.is_none());
+    }
+
+    #[test]
+    fn finished_transfers_cant_be_started_again() {
+        let mut lifetime = Lifetime::new();
+        lifetime.set_max_downloads(2);
+        let addr = "127.0.0.1:1".parse().unwrap();
+        lifetime.begin(1, 0, 1, (0, 10), addr, "file").unwrap();
+        lifetime.finish(1, 0, 10, None);
+
+        match lifetime.begin(1, 0, 1, (0, 10), addr, "file") {
+            Err(Error(ErrorKind::TransferDone(1), _)) => {},
+            res => panic!("Unexpected result: {:?}", res.map_err(|x| x.to_string())),
+        }
+        //The slot that is left still goes to a new transfer
+        lifetime.begin(2, 0, 1, (0, 10), addr, "file").unwrap();
+    }
+
+    #[test]
+    fn output_path_only_takes_plain_names() {
+        assert_eq!(output_path("file.txt", None).unwrap(), PathBuf::from("file.txt"));
+        for name in &["", ".", "..", "../file", "/etc/passwd", "dir/file", "./file"] {
+            match output_path(name, None) {
+                Err(Error(ErrorKind::UnsafeName(ref unsafe_name), _)) => assert_eq!(unsafe_name, name),
+                res => panic!("Unexpected result for {:?}: {:?}", name, res.map_err(|x| x.to_string())),
+            }
+        }
+        //What we were told to use goes, whatever the sender called it
+        assert_eq!(output_path("../file", Some(PathBuf::from("out/file"))).unwrap(), PathBuf::from("out/file"));
+    }
+
+    //Answers a request with the given frame after a while
+    fn fake_candidate(delay: u64, frame: Vec<u8>) -> std::net::SocketAddrV4 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Request::read(&mut stream).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+            let _ = stream.write_all(&frame);
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+        return std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(127, 0, 0, 1), port);
+    }
+
+    fn error_frame() -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_error(&mut frame, ERROR_UNAVAILABLE, &ErrorKind::Unavailable.into()).unwrap();
+        return frame;
+    }
+
+    fn test_request() -> Request {
+        return Request {
+            file: 0,
+            transfer: 1,
+            part: 0,
+            parts: 1,
+            range: ByteRange::whole(),
+            codecs: 0,
+        };
+    }
+
+    #[test]
+    fn race_skips_candidates_answering_with_errors() {
+        let failing = fake_candidate(0, error_frame());
+        let serving = fake_candidate(300, vec![FRAME_FILE]);
+        let (_, addr) = FileClient::new().race(&[failing, serving], Some(&test_request())).unwrap();
+        assert_eq!(addr, serving);
+    }
+
+    #[test]
+    fn race_reports_errors_when_nobody_serves() {
+        let failing = fake_candidate(0, error_frame());
+        match FileClient::new().race(&[failing], Some(&test_request())) {
+            Err(Error(ErrorKind::Remote(code, _), _)) => assert_eq!(code, ERROR_UNAVAILABLE),
+            Err(err) => panic!("Unexpected error {}", err),
+            Ok(_) => panic!("A candidate answering with an error won"),
+        }
+    }
+
+    #[test]
+    fn max_payload_has_exactly_bits_set() {
+        for bits in 1..200 {
+            let payload = max_payload(bits);
+            assert_eq!(payload.len(), ((bits + 7) / 8) as usize);
+            assert_eq!(payload.iter().map(|x| x.count_ones()).sum::<u32>(), bits);
+        }
+    }
+
+    #[test]
+    fn divide_undoes_multiply_add() {
+        let mut random = Random(0xD1B54A32D192ED03);
+        for bits in 1..200 {
+            let payload = random.payload(bits);
+            let divisor = (random.next() % 0xFFFF) as u32 + 2;
+            let addend = (random.next() % divisor as u64) as u32;
+
+            let mut grown = payload.clone();
+            multiply_add_payload(&mut grown, divisor, addend);
+            assert_eq!(divide_payload(&mut grown, divisor), addend);
+            assert_eq!(fixed_payload(&grown, payload.len()).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn fixed_payload_pads_and_refuses_overflow() {
+        assert_eq!(fixed_payload(&[], 2).unwrap(), vec![0, 0]);
+        assert_eq!(fixed_payload(&[0, 0, 0, 1], 2).unwrap(), vec![0, 1]);
+        assert_eq!(fixed_payload(&[0xFF, 0xFF], 2).unwrap(), vec![0xFF, 0xFF]);
+        assert!(fixed_payload(&[1, 0, 0], 2).is_err());
+    }
 }
@@ -0,0 +1,246 @@
+use std;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+//Limited transfers go in pieces this size, so they come out smooth instead of in bursts
+pub const CHUNK_SIZE: usize = 16 * 1024;
+//How much can be saved up while nothing is sent
+const BURST_SECS: f64 = 1.0;
+//Waiting is done in short naps, so a new rate is noticed quickly
+const MAX_SLEEP_MILLIS: u64 = 100;
+//Stored in place of a rate when there is no limit. A rate of 0 is a limit that lets nothing through
+const UNLIMITED: u64 = std::u64::MAX;
+
+//Bytes per second. Cloning shares it, so it can be changed while transfers are using it
+#[derive(Clone, Debug)]
+pub struct Rate(Arc<AtomicU64>);
+
+impl Rate {
+    pub fn new(rate: Option<u64>) -> Self {
+        return Rate(Arc::new(AtomicU64::new(rate.unwrap_or(UNLIMITED))));
+    }
+
+    //None lifts the limit
+    pub fn set(&self, rate: Option<u64>) {
+        self.0.store(rate.unwrap_or(UNLIMITED), Ordering::SeqCst);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        return match self.0.load(Ordering::SeqCst) {
+            UNLIMITED => None,
+            rate => Some(rate),
+        };
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+//A token bucket. Every byte takes a token, and tokens come in at the rate
+pub struct RateLimit {
+    rate: Rate,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimit {
+    pub fn new(rate: Rate) -> Self {
+        return RateLimit {
+            rate: rate,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        };
+    }
+
+    //Blocks until len bytes are allowed through
+    pub fn take(&self, len: usize) {
+        let mut needed = len as f64;
+        loop {
+            let rate = match self.rate.get() {
+                Some(rate) => rate as f64,
+                None => return,
+            };
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now - bucket.last;
+                let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+                bucket.last = now;
+                bucket.tokens = (bucket.tokens + elapsed * rate).min(rate * BURST_SECS);
+                let granted = needed.min(bucket.tokens);
+                bucket.tokens -= granted;
+                needed -= granted;
+                if needed <= 0.0 {
+                    return;
+                }
+                needed / rate
+            };
+            let wait = Duration::from_millis(std::cmp::min((wait * 1000.0).ceil() as u64, MAX_SLEEP_MILLIS));
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+//All the limits a transfer has to stay under, like one of its own and one shared with everyone
+#[derive(Clone)]
+pub struct Limits {
+    limits: Vec<Arc<RateLimit>>,
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        return Limits {
+            limits: Vec::new(),
+        };
+    }
+
+    pub fn add(&mut self, limit: Arc<RateLimit>) {
+        self.limits.push(limit);
+    }
+
+    pub fn is_limited(&self) -> bool {
+        return self.limits.iter().any(|x| x.rate.get().is_some());
+    }
+
+    //How much to send in one go
+    pub fn chunk(&self, len: usize) -> usize {
+        if self.is_limited() {
+            return std::cmp::min(len, CHUNK_SIZE);
+        }
+        return len;
+    }
+
+    pub fn take(&self, len: usize) {
+        for limit in self.limits.iter() {
+            limit.take(len);
+        }
+    }
+}
+
+//Keeps reads or writes under the limits
+pub struct Throttled<T> {
+    inner: T,
+    limits: Limits,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, limits: Limits) -> Self {
+        return Throttled {
+            inner: inner,
+            limits: limits,
+        };
+    }
+}
+
+impl<T: Read> Read for Throttled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.limits.chunk(buf.len());
+        let read = self.inner.read(&mut buf[..len])?;
+        self.limits.take(read);
+        return Ok(read);
+    }
+}
+
+impl<T: Write> Write for Throttled<T> {
+    //Writes can be partial, so only what got written is paid for, once it's out
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.limits.chunk(buf.len());
+        let written = self.inner.write(&buf[..len])?;
+        self.limits.take(written);
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(duration: Duration) -> f64 {
+        return duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+    }
+
+    //Takes at most 100 bytes per write
+    struct Trickle(usize);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len(), 100);
+            self.0 += len;
+            return Ok(len);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn take_waits_for_tokens() {
+        let limit = RateLimit::new(Rate::new(Some(100_000)));
+        let start = Instant::now();
+        limit.take(50_000);
+        let first = secs(start.elapsed());
+        assert!(first >= 0.45 && first < 0.75, "took {}s", first);
+
+        limit.take(25_000);
+        let second = secs(start.elapsed());
+        assert!(second >= 0.7 && second < 1.0, "took {}s", second);
+    }
+
+    #[test]
+    fn take_saves_up_a_burst_at_most() {
+        let limit = RateLimit::new(Rate::new(Some(10_000)));
+        std::thread::sleep(Duration::from_millis(1500));
+        let start = Instant::now();
+        limit.take(10_000);
+        assert!(secs(start.elapsed()) < 0.1);
+        limit.take(5_000);
+        let waited = secs(start.elapsed());
+        assert!(waited >= 0.45 && waited < 0.75, "took {}s", waited);
+    }
+
+    #[test]
+    fn take_notices_a_lifted_limit() {
+        let rate = Rate::new(Some(1));
+        let limit = RateLimit::new(rate.clone());
+        let lift = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            rate.set(None);
+        });
+        let start = Instant::now();
+        limit.take(1_000_000);
+        assert!(secs(start.elapsed()) < 0.5);
+        lift.join().unwrap();
+    }
+
+    #[test]
+    fn unlimited_is_not_zero() {
+        assert_eq!(Rate::new(None).get(), None);
+        assert_eq!(Rate::new(Some(0)).get(), Some(0));
+        let rate = Rate::new(Some(5));
+        rate.set(None);
+        assert_eq!(rate.get(), None);
+    }
+
+    #[test]
+    fn partial_writes_only_pay_for_what_was_written() {
+        let mut limits = Limits::new();
+        limits.add(Arc::new(RateLimit::new(Rate::new(Some(1_000)))));
+        let mut throttled = Throttled::new(Trickle(0), limits);
+        let start = Instant::now();
+        throttled.write_all(&[0u8; 500]).unwrap();
+        let took = secs(start.elapsed());
+        assert_eq!(throttled.inner.0, 500);
+        assert!(took >= 0.45 && took < 0.75, "took {}s", took);
+    }
+}
@@ -6,16 +6,22 @@ extern crate ansi_term;
 extern crate pbr;
 extern crate send;
 
+mod prompt;
+mod signal;
+
 use std::path::PathBuf;
 use clap::App;
 use clap::SubCommand;
 use clap::Arg;
 use std::error::Error;
 use std::io;
+use std::io::Write;
 use std::fmt;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use ansi_term::Colour::*;
+use send::Transportable;
+use send::print_err;
 
 #[derive(Debug)]
 pub enum AppError {
@@ -50,17 +56,273 @@ impl From<io::Error> for AppError {
     }
 }
 
-fn print_err<T: std::fmt::Display + std::error::Error>(err: T) {
-    println!(" {} {}", Red.paint("==>"), err);
-    let mut terr : &std::error::Error = &err;
-    while let Some(serr) = terr.cause() {
-        println!("    {} {}", Yellow.paint("==>"), serr);
-        terr = serr;
+const RELAY_ENV: &'static str = "SEND_RELAY";
+
+//How long running transfers get to finish after we are told to stop
+const DRAIN_SECS: u64 = 30;
+const SIGNAL_POLL_MILLIS: u64 = 100;
+
+//Show how to reach an interface. The qr flags are optional, so commands without them just get
+//the text
+fn print_key(presenter: &send::TransportPresenter, matches: &clap::ArgMatches, name: &str, transport: &send::ServerTransport, uri: Option<&str>) {
+    let words = presenter.present(transport).unwrap();
+    println!("{}\n {} {}",
+             Yellow.paint(name.to_string()),
+             Blue.paint("=>"),
+             words
+            );
+    if let Some(uri) = uri {
+        println!(" {} {}", Blue.paint("=>"), uri);
+    }
+    let qr = send::QrPresenter::new();
+    if matches.is_present("qr") {
+        println!("{}", qr.present(&words).unwrap());
+    } else if matches.is_present("qr-uri") {
+        println!("{}", qr.present(uri.unwrap_or(&words)).unwrap());
+    }
+}
+
+//One key for every address, for when it isn't clear which network the other side shares with
+//us. Loopback is left out, since it would point the other side back at itself.
+fn print_combined_key(presenter: &send::TransportPresenter, matches: &clap::ArgMatches, addrs: &[std::net::Ipv4Addr]) {
+    let addrs = addrs.iter()
+        .filter(|x| !x.is_loopback())
+        .cloned()
+        .collect::<Vec<_>>();
+    if addrs.len() < 2 {
+        return;
+    }
+    let transport = send::AddressList::new(addrs).make_transport().unwrap();
+    print_key(presenter, matches, "all interfaces", &transport, None);
+}
+
+//The relay can be given on the commandline or in the environment, so it doesn't have to be typed
+//every time
+fn relay_arg(matches: &clap::ArgMatches) -> send::errors::Result<Option<std::net::SocketAddrV4>> {
+    let relay = match matches.value_of("relay") {
+        Some(relay) => relay.to_owned(),
+        None => match std::env::var(RELAY_ENV) {
+            Ok(relay) => relay,
+            Err(_) => return Ok(None),
+        },
+    };
+    return send::DirectTransport::parse_with_port(&relay, send::relay::DEFAULT_RELAY_PORT)
+        .map(|x| Some(x.socket_addr()));
+}
+
+fn number_arg<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str) -> send::errors::Result<Option<T>> {
+    return match matches.value_of(name) {
+        Some(number) => number.parse::<T>()
+            .map(Some)
+            .map_err(|_| send::errors::ErrorKind::InvalidNumber(number.to_owned()).into()),
+        None => Ok(None),
+    };
+}
+
+//The timeouts from the commandline, with the defaults if none were given
+fn timeouts_arg(matches: &clap::ArgMatches) -> send::errors::Result<send::Timeouts> {
+    let mut timeouts = send::Timeouts::default();
+    if let Some(secs) = number_arg::<u64>(matches, "timeout")? {
+        timeouts.connect = std::time::Duration::from_secs(secs);
+        timeouts.idle = std::time::Duration::from_secs(secs);
+    }
+    return Ok(timeouts);
+}
+
+//Set up the timeouts and retries from the commandline, leaving the defaults for anything not given
+fn configure_client(client: &mut send::FileClient, matches: &clap::ArgMatches) -> send::errors::Result<()> {
+    client.set_timeouts(timeouts_arg(matches)?);
+    if let Some(retries) = number_arg(matches, "retries")? {
+        client.set_retries(retries);
+    }
+    return Ok(());
+}
+
+fn duration_arg(matches: &clap::ArgMatches, name: &str) -> send::errors::Result<Option<std::time::Duration>> {
+    return match matches.value_of(name) {
+        Some(duration) => send::parse_duration(duration).map(Some),
+        None => Ok(None),
+    };
+}
+
+fn lifetime_arg(matches: &clap::ArgMatches) -> send::errors::Result<send::Lifetime> {
+    let mut lifetime = send::Lifetime::new();
+    if matches.is_present("once") {
+        lifetime.set_max_downloads(1);
+    }
+    if let Some(max_downloads) = number_arg(matches, "max-downloads")? {
+        lifetime.set_max_downloads(max_downloads);
+    }
+    if let Some(idle_timeout) = duration_arg(matches, "idle-timeout")? {
+        lifetime.set_idle_timeout(idle_timeout);
+    }
+    if let Some(expires_in) = duration_arg(matches, "expires-in")? {
+        lifetime.set_expires_in(expires_in);
+    }
+    return Ok(lifetime);
+}
+
+fn rate_arg(matches: &clap::ArgMatches, name: &str) -> send::errors::Result<send::limit::Rate> {
+    return match matches.value_of(name) {
+        Some(rate) => send::parse_rate(rate).map(|x| send::limit::Rate::new(Some(x))),
+        None => Ok(send::limit::Rate::new(None)),
+    };
+}
+
+fn links_arg(matches: &clap::ArgMatches) -> send::errors::Result<send::archive::Links> {
+    return match matches.value_of("links") {
+        Some(links) => send::archive::Links::parse(links),
+        None => Ok(send::archive::Links::Follow),
+    };
+}
+
+//Only directories are filtered, but asking for it on a file is harmless
+fn filter_arg(matches: &clap::ArgMatches, path: &std::path::Path) -> send::errors::Result<send::archive::Filter> {
+    let mut filter = send::archive::Filter::new(path);
+    filter.set_links(links_arg(matches)?);
+    filter.set_special(matches.is_present("special"));
+    for pattern in matches.values_of("exclude").into_iter().flat_map(|x| x) {
+        filter.add_exclude(pattern)?;
+    }
+    for file in matches.values_of("exclude-from").into_iter().flat_map(|x| x) {
+        filter.add_exclude_from(std::path::Path::new(file))?;
+    }
+    filter.set_ignore_files(matches.is_present("gitignore"));
+    return Ok(filter);
+}
+
+fn describe_kind(kind: &send::archive::Kind, size: u64) -> String {
+    use send::archive::Kind;
+
+    return match *kind {
+        Kind::Dir => "directory".to_owned(),
+        Kind::File => format!("{} bytes", size),
+        Kind::Symlink(ref target) => format!("link to {}", target.display()),
+        Kind::HardLink(ref first) => format!("same file as {}", first.display()),
+        Kind::Special => "device or FIFO".to_owned(),
+    };
+}
+
+//Says how much would be served and what has to be left out. With dry_run every file is listed
+//the way it would be served
+fn print_listing(file: &send::FileInfo, dry_run: bool) -> send::errors::Result<()> {
+    use send::archive::Kind;
+
+    let (files, skipped) = if file.is_dir() {
+        let (entries, skipped) = file.entries()?;
+        let files = entries.into_iter()
+            .filter(|x| x.kind != Kind::Dir)
+            .map(|x| (x.name, x.kind, x.metadata.len()))
+            .collect::<Vec<_>>();
+        (files, skipped)
+    } else {
+        (vec![(file.path().to_owned(), file.kind().clone(), file.size())], Vec::new())
+    };
+    if dry_run {
+        for &(ref name, ref kind, size) in files.iter() {
+            println!(" {} {} ({})", Blue.paint("=>"), name.display(), describe_kind(kind, size));
+        }
+    }
+    for skipped in skipped.iter() {
+        println!(" {} {}, leaving it out", Red.paint("=>"), skipped.reason);
+    }
+    println!("{} {} file(s) ({} bytes)",
+             Green.paint(if dry_run { "Would serve" } else { "Serving" }),
+             files.len(),
+             files.iter().filter(|x| x.1 == Kind::File).map(|x| x.2).sum::<u64>());
+    return Ok(());
+}
+
+//Rates can be changed while running by typing their name and the new rate, like "limit 500K".
+//"off" lifts the limit
+fn watch_rates(rates: Vec<(&'static str, send::limit::Rate)>) {
+    use std::io::BufRead;
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            continue;
+        }
+        let rate = match rates.iter().find(|x| x.0 == words[0]) {
+            Some(&(_, ref rate)) if words.len() == 2 => rate,
+            _ => {
+                let names = rates.iter().map(|x| x.0).collect::<Vec<_>>();
+                eprintln!("{} {} followed by a rate or off", Red.paint("Expected"), names.join(" or "));
+                continue;
+            }
+        };
+        if words[1] == "off" {
+            rate.set(None);
+            println!("{} {}", Green.paint("Lifted"), words[0]);
+            continue;
+        }
+        match send::parse_rate(words[1]) {
+            Ok(new_rate) => {
+                rate.set(Some(new_rate));
+                println!("{} {} to {} bytes per second", Green.paint("Set"), words[0], new_rate);
+            }
+            Err(err) => print_err(err),
+        }
+    }
+}
+
+//The first signal stops new downloads. Running ones are aborted by a second signal, or when they
+//haven't finished in time
+fn watch_signals(lifetime: Arc<send::Lifetime>) {
+    let poll = std::time::Duration::from_millis(SIGNAL_POLL_MILLIS);
+    while signal::received() == 0 {
+        std::thread::sleep(poll);
+    }
+    lifetime.stop("the server was interrupted");
+    if lifetime.active() == 0 {
+        return;
+    }
+    println!("{} running transfers, interrupt again to abort them", Yellow.paint("Finishing"));
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(DRAIN_SECS);
+    while lifetime.active() > 0 {
+        if signal::received() > 1 || std::time::Instant::now() >= deadline {
+            lifetime.abort("the server was interrupted");
+            return;
+        }
+        std::thread::sleep(poll);
+    }
+}
+
+fn print_summary(lifetime: &send::Lifetime) {
+    let downloads = lifetime.downloads();
+    println!("{} because {}, {} download(s)",
+             Green.paint("Stopped"),
+             lifetime.stopped().unwrap_or("the server shut down"),
+             downloads.iter().filter(|x| x.error.is_none()).count());
+    for download in downloads {
+        match download.error {
+            None => println!(" {} {} ({} bytes) to {}",
+                             Blue.paint("=>"),
+                             download.name,
+                             download.size,
+                             Yellow.paint(download.remote_addr.to_string())),
+            Some(err) => println!(" {} {} to {} failed: {}",
+                                  Red.paint("=>"),
+                                  download.name,
+                                  Yellow.paint(download.remote_addr.to_string()),
+                                  err),
+        }
     }
 }
 
+fn size_arg(matches: &clap::ArgMatches, name: &str) -> send::errors::Result<Option<u64>> {
+    return match matches.value_of(name) {
+        Some(size) => send::parse_size(size).map(Some),
+        None => Ok(None),
+    };
+}
+
 //@Refactor: Move file opening and duplicate detection somewhere else?
-//@MEMORY @SPEED This has a real slow and real bad generated function. It's terrible!
 include!(concat!(env!("OUT_DIR"), "/words.rs"));
 
 fn main() {
@@ -69,13 +331,13 @@ fn main() {
         .author("Jesper Jensen")
         .about("A program to send files")
         .subcommand(SubCommand::with_name("serve")
-                    .about("Serve a file")
+                    .about("Serve a file, or a directory as an archive")
                     .arg(Arg::with_name("file")
                          .index(1)
                          .required(true)
                          .multiple(false)
                          .value_name("FILE")
-                         .help("File to serve")
+                         .help("File or directory to serve")
                         )
                     .arg(Arg::with_name("port")
                          .short("p")
@@ -83,34 +345,347 @@ fn main() {
                          .value_name("PORT")
                          .help("Port to send on")
                         )
+                    .arg(Arg::with_name("subnet")
+                         .short("s")
+                         .long("subnet")
+                         .help("Only put the host part of the address in the key")
+                        )
+                    .arg(Arg::with_name("qr")
+                         .long("qr")
+                         .help("Show the key as a QR code")
+                        )
+                    .arg(Arg::with_name("qr-uri")
+                         .long("qr-uri")
+                         .conflicts_with("qr")
+                         .help("Show the direct address as a QR code")
+                        )
+                    .arg(Arg::with_name("relay")
+                         .short("r")
+                         .long("relay")
+                         .value_name("ADDR")
+                         .help("Relay to use when a direct connection isn't possible. Defaults to $SEND_RELAY")
+                        )
+                    .arg(Arg::with_name("timeout")
+                         .short("t")
+                         .long("timeout")
+                         .value_name("SECS")
+                         .help("Give up on a stalled transfer after this long")
+                        )
+                    .arg(Arg::with_name("once")
+                         .long("once")
+                         .conflicts_with("max-downloads")
+                         .help("Stop after the first download")
+                        )
+                    .arg(Arg::with_name("max-downloads")
+                         .long("max-downloads")
+                         .value_name("N")
+                         .help("Stop after this many downloads")
+                        )
+                    .arg(Arg::with_name("idle-timeout")
+                         .long("idle-timeout")
+                         .value_name("DURATION")
+                         .help("Stop when nobody has connected for this long, like 10m")
+                        )
+                    .arg(Arg::with_name("expires-in")
+                         .long("expires-in")
+                         .value_name("DURATION")
+                         .help("Stop after this long no matter what, like 2h")
+                        )
+                    .arg(Arg::with_name("limit")
+                         .long("limit")
+                         .value_name("RATE")
+                         .help("Bytes per second for all downloads together, like 2M. Type \"limit RATE\" or \"limit off\" to change it while running")
+                        )
+                    .arg(Arg::with_name("connection-limit")
+                         .long("connection-limit")
+                         .value_name("RATE")
+                         .help("Bytes per second for each connection. Type \"connection-limit RATE\" to change it while running")
+                        )
+                    .arg(Arg::with_name("zero-copy")
+                         .long("zero-copy")
+                         .help("Send plain files straight from the page cache. Faster, but the content goes without a checksum")
+                        )
+                    .arg(Arg::with_name("exclude")
+                         .long("exclude")
+                         .value_name("PATTERN")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Leave out what matches, written like a line of a .gitignore, like target/ or *.log. Can be given more than once")
+                        )
+                    .arg(Arg::with_name("exclude-from")
+                         .long("exclude-from")
+                         .value_name("FILE")
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("Leave out what matches the patterns in the file, one per line")
+                        )
+                    .arg(Arg::with_name("gitignore")
+                         .long("gitignore")
+                         .help("Leave out what .gitignore and .ignore files in the directory ignore, and .git itself")
+                        )
+                    .arg(Arg::with_name("links")
+                         .long("links")
+                         .value_name("MODE")
+                         .possible_values(&["follow", "preserve", "skip"])
+                         .help("What to do with symbolic links: send what they point to, send them as links or leave them out. Defaults to follow")
+                        )
+                    .arg(Arg::with_name("special")
+                         .long("special")
+                         .help("Send the devices and FIFOs in a directory instead of leaving them out")
+                        )
+                    .arg(Arg::with_name("dry-run")
+                         .long("dry-run")
+                         .help("List what would be served and how big it is, without serving it")
+                        )
                     )
         .subcommand(SubCommand::with_name("fetch")
                     .about("Fetch a file")
                     .arg(Arg::with_name("key")
                          .index(1)
-                         .required(true)
                          .multiple(true)
                          .value_name("KEY")
-                         .help("Key of remote file")
+                         .help("Key or address of remote file. Asked for if not given")
+                        )
+                    .arg(Arg::with_name("file")
+                         .short("f")
+                         .long("file")
+                         .value_name("FILE")
+                         .help("Filename of the new file or directory, or - for stdout. Directories come to stdout as a tar archive")
+                        )
+                    .arg(Arg::with_name("range")
+                         .long("range")
+                         .value_name("START-END")
+                         .allow_hyphen_values(true)
+                         .help("Only fetch these bytes. Ends are inclusive and can be left out, like 1M- or -10K for the last 10K")
+                        )
+                    .arg(Arg::with_name("subnet")
+                         .short("s")
+                         .long("subnet")
+                         .help("The key only holds the host part of the address")
+                        )
+                    .arg(Arg::with_name("relay")
+                         .short("r")
+                         .long("relay")
+                         .value_name("ADDR")
+                         .help("Relay to use when a direct connection isn't possible. Defaults to $SEND_RELAY")
+                        )
+                    .arg(Arg::with_name("timeout")
+                         .short("t")
+                         .long("timeout")
+                         .value_name("SECS")
+                         .help("Give up on connecting or on a stalled transfer after this long")
+                        )
+                    .arg(Arg::with_name("retries")
+                         .long("retries")
+                         .value_name("N")
+                         .help("How many times to retry connecting")
+                        )
+                    .arg(Arg::with_name("fsync")
+                         .long("fsync")
+                         .help("Make sure the file is on disk before calling it done")
+                        )
+                    .arg(Arg::with_name("no-compress")
+                         .long("no-compress")
+                         .help("Don't let the server compress the file")
+                        )
+                    .arg(Arg::with_name("special")
+                         .long("special")
+                         .help("Make the devices and FIFOs in a directory instead of refusing them")
                         )
+                    .arg(Arg::with_name("limit")
+                         .long("limit")
+                         .value_name("RATE")
+                         .help("Bytes per second, like 2M. Type \"limit RATE\" or \"limit off\" to change it while running")
+                        )
+                    .arg(Arg::with_name("streams")
+                         .long("streams")
+                         .value_name("N")
+                         .help("Fetch over this many connections at once. The server might allow fewer")
+                        )
+                    )
+        .subcommand(SubCommand::with_name("relay")
+                    .about("Connect peers that can't reach each other")
+                    .arg(Arg::with_name("port")
+                         .short("p")
+                         .long("port")
+                         .value_name("PORT")
+                         .help("Port to listen on")
+                        )
+                    .arg(Arg::with_name("timeout")
+                         .short("t")
+                         .long("timeout")
+                         .value_name("SECS")
+                         .help("Give up on a stalled connection after this long")
+                        )
+                    )
+        .subcommand(SubCommand::with_name("receive")
+                    .about("Wait for a file to be pushed")
                     .arg(Arg::with_name("file")
                          .short("f")
                          .long("file")
                          .value_name("FILE")
                          .help("Filename of the new file")
                         )
+                    .arg(Arg::with_name("subnet")
+                         .short("s")
+                         .long("subnet")
+                         .help("Only put the host part of the address in the key")
+                        )
+                    .arg(Arg::with_name("timeout")
+                         .short("t")
+                         .long("timeout")
+                         .value_name("SECS")
+                         .help("Give up on a stalled transfer after this long")
+                        )
+                    )
+        .subcommand(SubCommand::with_name("inbox")
+                    .about("Collect files pushed by others into a directory")
+                    .arg(Arg::with_name("dir")
+                         .index(1)
+                         .required(true)
+                         .value_name("DIR")
+                         .help("Directory to store the files in")
+                        )
+                    .arg(Arg::with_name("max-size")
+                         .long("max-size")
+                         .value_name("SIZE")
+                         .help("Largest file to accept, like 500M")
+                        )
+                    .arg(Arg::with_name("quota")
+                         .long("quota")
+                         .value_name("SIZE")
+                         .help("Total size of all files to accept, like 10G")
+                        )
+                    .arg(Arg::with_name("approve")
+                         .short("a")
+                         .long("approve")
+                         .help("Ask before accepting each file")
+                        )
+                    .arg(Arg::with_name("subnet")
+                         .short("s")
+                         .long("subnet")
+                         .help("Only put the host part of the address in the key")
+                        )
+                    .arg(Arg::with_name("qr")
+                         .long("qr")
+                         .help("Show the key as a QR code")
+                        )
+                    .arg(Arg::with_name("qr-uri")
+                         .long("qr-uri")
+                         .conflicts_with("qr")
+                         .help("Show the direct address as a QR code")
+                        )
+                    .arg(Arg::with_name("timeout")
+                         .short("t")
+                         .long("timeout")
+                         .value_name("SECS")
+                         .help("Give up on a stalled transfer after this long")
+                        )
+                    )
+        .subcommand(SubCommand::with_name("push")
+                    .about("Push a file to a receiver")
+                    .arg(Arg::with_name("args")
+                         .index(1)
+                         .required(true)
+                         .multiple(true)
+                         .min_values(2)
+                         .value_names(&["KEY", "FILE"])
+                         .help("Key or address of the receiver followed by the file to push")
+                        )
+                    .arg(Arg::with_name("subnet")
+                         .short("s")
+                         .long("subnet")
+                         .help("The key only holds the host part of the address")
+                        )
+                    .arg(Arg::with_name("timeout")
+                         .short("t")
+                         .long("timeout")
+                         .value_name("SECS")
+                         .help("Give up on connecting or on a stalled transfer after this long")
+                        )
+                    .arg(Arg::with_name("retries")
+                         .long("retries")
+                         .value_name("N")
+                         .help("How many times to retry connecting")
+                        )
+                    .arg(Arg::with_name("zero-copy")
+                         .long("zero-copy")
+                         .help("Send plain files straight from the page cache. Faster, but the content goes without a checksum")
+                        )
                     ).get_matches();
 
-    let (glob_lines, glob_count) = make_list();
-    let presenter = send::TransportPresenter::new(glob_lines, glob_count);
+    let presenter = send::TransportPresenter::new(make_list());
 
     if let Some(matches) = matches.subcommand_matches("serve") {
         //We know that file has to be provided
         let path = PathBuf::from(matches.value_of("file").unwrap());
+        let subnet = matches.is_present("subnet");
 
-        let file = send::FileInfo::from_path(path)
-            .expect("Failed opening file");
+        let file = filter_arg(matches, &path).and_then(|filter| {
+            let mut file = send::FileInfo::from_path(path.clone(), links_arg(matches)?)?;
+            file.set_filter(filter);
+            return Ok(file);
+        });
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        if matches.is_present("dry-run") {
+            if let Err(err) = print_listing(&file, true) {
+                print_err(err);
+            }
+            return;
+        }
+        //The archive is made anew for each download, so this is only how it looks now
+        if file.is_dir() {
+            if let Err(err) = print_listing(&file, false) {
+                print_err(err);
+                return;
+            }
+        }
+        let relay = match relay_arg(matches) {
+            Ok(relay) => relay,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let timeouts = match timeouts_arg(matches) {
+            Ok(timeouts) => timeouts,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let lifetime = match lifetime_arg(matches) {
+            Ok(lifetime) => Arc::new(lifetime),
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let rates = rate_arg(matches, "limit")
+            .and_then(|rate| rate_arg(matches, "connection-limit").map(|connection_rate| (rate, connection_rate)));
+        let (rate, connection_rate) = match rates {
+            Ok(rates) => rates,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let limit = Arc::new(send::limit::RateLimit::new(rate.clone()));
+        let rates = vec![("limit", rate), ("connection-limit", connection_rate.clone())];
+        std::thread::spawn(move || watch_rates(rates));
 
+        if let Err(err) = signal::install() {
+            print_err(err);
+            return;
+        }
+        let watched = lifetime.clone();
+        std::thread::spawn(move || watch_signals(watched));
 
         let interfaces = send::network::interfaces().unwrap();
         let mut thread = Vec::with_capacity(interfaces.len());
@@ -120,47 +695,324 @@ fn main() {
             info!("Interface: {}", interface.name);
             let repo = send::FileRepository::new(interface);
             let key = repo.interface.name.clone();
-            let repo_ref = Mutex::new(repo);
-            imap.insert(key, repo_ref);
+            imap.insert(key, repo);
         }
 
-        for (key, repo_ref) in imap {
-            {
-                let mut repo = repo_ref.lock().unwrap();
-                let transport = repo.add_file(file.clone()).unwrap();
-                println!("{}\n {} {}",
-                         Yellow.paint(key.to_string()),
-                         Blue.paint("=>"),
-                         presenter.present(&transport).unwrap()
-                        );
+        let addrs = imap.values()
+            .map(|x| x.interface.addr)
+            .collect::<Vec<_>>();
+        for (key, mut repo) in imap {
+            repo.set_timeouts(timeouts);
+            repo.set_lifetime(lifetime.clone());
+            repo.set_limit(limit.clone());
+            repo.set_connection_rate(connection_rate.clone());
+            repo.set_zero_copy(matches.is_present("zero-copy"));
+            let mut transport = repo.add_file(file.clone()).unwrap();
+            if subnet {
+                transport = send::HostAddr::from_interface(&repo.interface).make_transport().unwrap();
+            }
+            print_key(&presenter, matches, &key, &transport, Some(&repo.uri()));
+            if relay.is_some() {
+                print_key(&presenter, matches, &format!("{} through the relay", key), &repo.relay_transport().unwrap(), None);
+            }
+
+            let repo = Arc::new(repo);
+            //Waiting at the relay can't be interrupted, so those threads are left behind when we
+            //are done
+            if let Some(relay) = relay {
+                let repo = repo.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = send::FileRepository::run_relay(repo, relay) {
+                        print_err(err)
+                    }
+                });
             }
             thread.push(std::thread::spawn(move || {
-                let repo = repo_ref.lock().unwrap();
-                if let Err(err) = repo.run() {
+                if let Err(err) = send::FileRepository::run(repo) {
                     print_err(err)
                 }
             }));
         }
+        if !subnet {
+            print_combined_key(&presenter, matches, &addrs);
+        }
 
         for t in thread {
             t.join().unwrap();
         }
+        //Transfers through the relay aren't on the listener threads
+        while lifetime.active() > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(SIGNAL_POLL_MILLIS));
+        }
+        print_summary(&lifetime);
     } else if let Some(matches) = matches.subcommand_matches("fetch") {
-        //There has to be a key for the commandline to be valid so just unwrap
-        let key = matches.values_of("key").unwrap()
-            .collect::<Vec<_>>()
-            .join(" ");
+        let subnet = matches.is_present("subnet");
+        let key = match matches.values_of("key") {
+            Some(key) => key.collect::<Vec<_>>().join(" "),
+            None => {
+                //Only subnet keys need the interfaces to be resolved
+                let interfaces = if subnet {
+                    match send::network::interfaces() {
+                        Ok(interfaces) => interfaces,
+                        Err(err) => {
+                            print_err(err);
+                            return;
+                        }
+                    }
+                } else {
+                    Vec::new()
+                };
+                let decode = |key: &str| -> Result<String, String> {
+                    let transport = presenter.present_inv(key.to_owned())
+                        .map_err(|err| err.to_string())?;
+                    let ips = if subnet {
+                        send::HostAddr::resolve(transport, &interfaces).map(|(ip, _)| ip.to_string())
+                    } else {
+                        send::candidates(transport).map(|x| {
+                            x.iter()
+                                .map(|x| x.ip().to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        })
+                    };
+                    return ips.map_err(|err| err.to_string());
+                };
+                match prompt::KeyPrompt::new(presenter.dictionary(), decode).read_key().unwrap() {
+                    Some(key) => key,
+                    None => return,
+                }
+            }
+        };
+        let stdout = matches.value_of("file") == Some("-");
         let new_path = matches.value_of("file")
+            .filter(|_| !stdout)
             .map(| path | std::path::PathBuf::from(path));
 
-        let transport = presenter.present_inv(key).unwrap();
-        let client = send::FileClient::new();
-
-        client.get_file(transport, new_path).unwrap();
+        let candidates = match send::resolve_key(&presenter, &key, subnet) {
+            Ok(candidates) => candidates,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let mut client = send::FileClient::new();
+        client.set_sync(matches.is_present("fsync"));
+        client.set_compression(!matches.is_present("no-compress"));
+        client.set_special(matches.is_present("special"));
+        client.set_stdout(stdout);
+        let relay = configure_client(&mut client, matches)
+            .and_then(|_| number_arg(matches, "streams"))
+            .map(|streams| if let Some(streams) = streams { client.set_streams(streams) })
+            .and_then(|_| match matches.value_of("range") {
+                Some(range) => send::ByteRange::parse(range).map(|range| client.set_range(range)),
+                None => Ok(()),
+            })
+            .and_then(|_| relay_arg(matches));
+        if let Some(code) = send::resolve_relay_code(&presenter, &key) {
+            client.set_relay_code(code);
+        }
+        match relay {
+            Ok(Some(relay)) => client.set_relay(relay),
+            Ok(None) => {},
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        }
+        let rate = match rate_arg(matches, "limit") {
+            Ok(rate) => rate,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        client.set_limit(Arc::new(send::limit::RateLimit::new(rate.clone())));
+        std::thread::spawn(move || watch_rates(vec![("limit", rate)]));
+        if let Err(err) = client.get_file(&candidates, new_path) {
+            print_err(err);
+        }
 
         // if let Err(err) = send::fetch_file(presenter, transport, new_path) {
         //     print_err(err);
         // }
+    } else if let Some(matches) = matches.subcommand_matches("relay") {
+        let port = match matches.value_of("port").map(|x| x.parse::<u16>()) {
+            Some(Ok(port)) => port,
+            Some(Err(_)) => {
+                println!(" {} Invalid port {}", Red.paint("==>"), matches.value_of("port").unwrap());
+                return;
+            }
+            None => send::relay::DEFAULT_RELAY_PORT,
+        };
+        let timeouts = match timeouts_arg(matches) {
+            Ok(timeouts) => timeouts,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let addr = std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(0, 0, 0, 0), port);
+        let mut relay = send::relay::Relay::new(addr);
+        relay.set_timeouts(timeouts);
+        println!("{} on port {}", Green.paint("Relaying"), Yellow.paint(port.to_string()));
+        if let Err(err) = send::relay::Relay::run(Arc::new(relay)) {
+            print_err(err);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("receive") {
+        let subnet = matches.is_present("subnet");
+        let new_path = matches.value_of("file")
+            .map(| path | std::path::PathBuf::from(path));
+        let timeouts = match timeouts_arg(matches) {
+            Ok(timeouts) => timeouts,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+
+        let interfaces = send::network::interfaces().unwrap();
+        if !subnet {
+            let addrs = interfaces.iter()
+                .map(|x| x.addr)
+                .collect::<Vec<_>>();
+            print_combined_key(&presenter, matches, &addrs);
+        }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for interface in interfaces {
+            let mut recv = send::FileReceiver::new(interface);
+            recv.set_timeouts(timeouts);
+            let transport = if subnet {
+                send::HostAddr::from_interface(&recv.interface).make_transport().unwrap()
+            } else {
+                recv.make_transport().unwrap()
+            };
+            print_key(&presenter, matches, &recv.interface.name, &transport, Some(&recv.uri()));
+
+            let sender = sender.clone();
+            let new_path = new_path.clone();
+            std::thread::spawn(move || {
+                sender.send(recv.run(new_path)).unwrap();
+            });
+        }
+        drop(sender);
+
+        //The first interface to get a file wins, the rest are left behind when we exit
+        for res in receiver {
+            match res {
+                Ok((remote_addr, path)) => {
+                    println!("{} {} from {}",
+                             Green.paint("Received"),
+                             path.to_string_lossy(),
+                             Yellow.paint(remote_addr.to_string()));
+                    return;
+                }
+                Err(err) => print_err(err),
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("inbox") {
+        let dir = PathBuf::from(matches.value_of("dir").unwrap());
+        if !dir.is_dir() {
+            println!(" {} {} is not a directory", Red.paint("==>"), dir.to_string_lossy());
+            return;
+        }
+        let (max_size, quota) = match (size_arg(matches, "max-size"), size_arg(matches, "quota")) {
+            (Ok(max_size), Ok(quota)) => (max_size, quota),
+            (Err(err), _) | (_, Err(err)) => {
+                print_err(err);
+                return;
+            }
+        };
+        let timeouts = match timeouts_arg(matches) {
+            Ok(timeouts) => timeouts,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let mut inbox = send::FileInbox::new(dir, max_size, quota);
+        inbox.set_timeouts(timeouts);
+        if let Err(err) = inbox.count_existing() {
+            print_err(err);
+            return;
+        }
+        if matches.is_present("approve") {
+            //Uploads come in on many threads, so only ask about one at a time
+            let asking = Mutex::new(());
+            inbox.set_approval(Box::new(move |upload| {
+                let _lock = asking.lock().unwrap();
+                print!("{} {} ({} bytes) from {}? [y/N] ",
+                       Green.paint("Accept"),
+                       upload.name,
+                       upload.size,
+                       Yellow.paint(upload.remote_addr.to_string()));
+                io::stdout().flush().unwrap();
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer).unwrap();
+                return answer.trim().eq_ignore_ascii_case("y");
+            }));
+        }
+        let inbox = std::sync::Arc::new(inbox);
+
+        let subnet = matches.is_present("subnet");
+        let interfaces = send::network::interfaces().unwrap();
+        if !subnet {
+            let addrs = interfaces.iter()
+                .map(|x| x.addr)
+                .collect::<Vec<_>>();
+            print_combined_key(&presenter, matches, &addrs);
+        }
+        let mut thread = Vec::with_capacity(interfaces.len());
+        for interface in interfaces {
+            let transport = if subnet {
+                send::HostAddr::from_interface(&interface).make_transport().unwrap()
+            } else {
+                interface.addr.make_transport().unwrap()
+            };
+            let uri = send::DirectTransport::new(interface.addr, send::DEFAULT_PORT).uri();
+            print_key(&presenter, matches, &interface.name, &transport, Some(&uri));
+
+            let inbox = inbox.clone();
+            thread.push(std::thread::spawn(move || {
+                if let Err(err) = send::FileInbox::run(inbox, interface.addr) {
+                    print_err(err)
+                }
+            }));
+        }
+
+        for t in thread {
+            t.join().unwrap();
+        }
+    } else if let Some(matches) = matches.subcommand_matches("push") {
+        //The key can be several words, and the file is always last
+        let mut args = matches.values_of("args").unwrap()
+            .collect::<Vec<_>>();
+        let path = PathBuf::from(args.pop().unwrap());
+        let key = args.join(" ");
+
+        //Receivers only take files, so there is nothing to do with a link but follow it
+        let file = match send::FileInfo::from_path(path, send::archive::Links::Follow) {
+            Ok(file) => file,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let candidates = match send::resolve_key(&presenter, &key, matches.is_present("subnet")) {
+            Ok(candidates) => candidates,
+            Err(err) => {
+                print_err(err);
+                return;
+            }
+        };
+        let mut client = send::FileClient::new();
+        client.set_zero_copy(matches.is_present("zero-copy"));
+        if let Err(err) = configure_client(&mut client, matches) {
+            print_err(err);
+            return;
+        }
+        if let Err(err) = client.push_file(&candidates, &file) {
+            print_err(err);
+        }
     }
     return;
 }
@@ -8,6 +8,10 @@ use std::mem;
 use std::io;
 use std::ffi;
 use std::cmp;
+use std::net::TcpStream;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum NetworkError {
@@ -61,14 +65,26 @@ impl From<ffi::IntoStringError> for NetworkError {
 pub struct Interface {
     pub name : String,
     pub addr : Ipv4Addr,
+    pub netmask : Ipv4Addr,
 }
 
 impl cmp::PartialEq for Interface {
     fn eq(&self, other: &Interface) -> bool {
-        return self.name == other.name && self.addr == other.addr;
+        return self.name == other.name && self.addr == other.addr && self.netmask == other.netmask;
     }
 }
 
+impl Interface {
+    pub fn network(&self) -> Ipv4Addr {
+        return Ipv4Addr::from(u32::from(self.addr) & u32::from(self.netmask));
+    }
+}
+
+fn sockaddr_to_ipv4(addr: *const libc::sockaddr) -> Ipv4Addr {
+    let data = unsafe{ (*addr).sa_data };
+    return Ipv4Addr::new(data[2] as u8, data[3] as u8, data[4] as u8, data[5] as u8);
+}
+
 pub fn interfaces() -> Result<Vec<Interface>, NetworkError> {
     info!("Getting interfaces");
     let mut interfaces = Vec::new();
@@ -91,15 +107,21 @@ pub fn interfaces() -> Result<Vec<Interface>, NetworkError> {
             let addr_info_ret = unsafe{ libc::getnameinfo((*thisaddr).ifa_addr, sock_size, hostname.as_mut_ptr(), hostname.capacity() as u32, std::ptr::null::<i8>() as *mut i8, 0, 1) };
             if addr_info_ret == 0 {
 
-                //@Memory: I don't know if this is correct
-                //@Leak: Might leak
-                let name = unsafe{ ffi::CString::from_raw((*thisaddr).ifa_name) };
-                let data = unsafe{ (*(*thisaddr).ifa_addr).sa_data };
-                let addr = Ipv4Addr::new(data[2] as u8, data[3] as u8, data[4] as u8, data[5] as u8);
+                //The name is owned by the ifaddrs list, so copy it out before it's freed
+                let name = unsafe{ ffi::CStr::from_ptr((*thisaddr).ifa_name) }.to_owned();
+                let addr = sockaddr_to_ipv4(unsafe{ (*thisaddr).ifa_addr });
+                //Without a netmask we treat the address as its own subnet
+                let netmask_ptr = unsafe{ (*thisaddr).ifa_netmask };
+                let netmask = if netmask_ptr.is_null() {
+                    Ipv4Addr::new(255, 255, 255, 255)
+                } else {
+                    sockaddr_to_ipv4(netmask_ptr)
+                };
 
                 let interface = Interface {
                     name: try!(name.into_string()),
                     addr: addr,
+                    netmask: netmask,
                 };
 
                 interfaces.push(interface);
@@ -117,3 +139,55 @@ pub fn interfaces() -> Result<Vec<Interface>, NetworkError> {
     unsafe{ libc::freeifaddrs(addrs) }
     return Ok(interfaces);
 }
+
+fn setsockopt_int(stream: &TcpStream, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe{ libc::setsockopt(stream.as_raw_fd(), level, name, &value as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+//Turn on TCP keepalive, so dead peers are noticed even when nothing is being sent
+pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    setsockopt_int(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    return set_keepalive_idle(stream, idle);
+}
+
+#[cfg(target_os = "linux")]
+fn set_keepalive_idle(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    let secs = cmp::max(idle.as_secs(), 1) as libc::c_int;
+    setsockopt_int(stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+    return setsockopt_int(stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs);
+}
+
+//@Expansion: Only linux lets us pick when the probes start. Everything else uses the system default
+#[cfg(not(target_os = "linux"))]
+fn set_keepalive_idle(_stream: &TcpStream, _idle: Duration) -> io::Result<()> {
+    return Ok(());
+}
+
+//Sends count bytes of the file from offset straight to the socket, without going through our
+//memory. Like write, it might send less than asked.
+#[cfg(target_os = "linux")]
+pub fn sendfile(stream: &TcpStream, file: &File, offset: u64, count: usize) -> io::Result<usize> {
+    let mut offset = offset as libc::off_t;
+    let ret = unsafe{ libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(ret as usize);
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sendfile(_stream: &TcpStream, _file: &File, _offset: u64, _count: usize) -> io::Result<usize> {
+    return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+}
+
+//Whether sendfile failed because it can't be used here, rather than something going wrong
+pub fn is_unsupported(err: &io::Error) -> bool {
+    return match err.raw_os_error() {
+        Some(libc::ENOSYS) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => true,
+        _ => false,
+    };
+}
@@ -0,0 +1,254 @@
+extern crate libc;
+
+use std::io;
+use std::io::{Read, Write, BufRead};
+use std::mem;
+use ansi_term::Colour::*;
+use send;
+use send::dictionary::Dictionary;
+
+//How many completions to show while typing
+const SHOWN_COMPLETIONS: usize = 5;
+
+const CTRL_C: u8 = 3;
+const CTRL_D: u8 = 4;
+const BACKSPACE: u8 = 8;
+const TAB: u8 = 9;
+const ESCAPE: u8 = 27;
+const DELETE: u8 = 127;
+
+//Puts the terminal in non canonical mode without echo for as long as it lives
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let mut original : libc::termios = unsafe{ mem::zeroed() };
+        if unsafe{ libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        //We handle Ctrl-C ourselves so the terminal is always restored
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe{ libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(RawMode {
+            original: original,
+        });
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe{ libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a.bytes()
+        .zip(b.bytes())
+        .take_while(|&(x, y)| x == y)
+        .count();
+    return &a[..len];
+}
+
+pub struct KeyPrompt<'a, F: Fn(&str) -> Result<String, String>> {
+    dictionary: Dictionary<'a>,
+    decode: F,
+    input: String,
+    //Message about the last action, cleared on the next keypress
+    message: Option<String>,
+}
+
+impl<'a, F: Fn(&str) -> Result<String, String>> KeyPrompt<'a, F> {
+    //decode turns a key made of valid words into a description of where it points
+    pub fn new(dictionary: Dictionary<'a>, decode: F) -> Self {
+        return KeyPrompt {
+            dictionary: dictionary,
+            decode: decode,
+            input: String::new(),
+            message: None,
+        };
+    }
+
+    //Returns None if the user gave up
+    pub fn read_key(&mut self) -> io::Result<Option<String>> {
+        if unsafe{ libc::isatty(libc::STDIN_FILENO) } == 0 {
+            //Not a terminal, so there is nobody to complete for. Just take the first line
+            let stdin = io::stdin();
+            let mut line = String::new();
+            stdin.lock().read_line(&mut line)?;
+            let line = line.trim();
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(line.to_owned()));
+        }
+
+        let _raw = RawMode::enable()?;
+        let stdin = io::stdin();
+        let mut bytes = stdin.lock().bytes();
+
+        self.draw()?;
+        while let Some(byte) = bytes.next() {
+            self.message = None;
+            match byte? {
+                CTRL_C | CTRL_D => {
+                    println!("");
+                    return Ok(None);
+                }
+                b'\r' | b'\n' => {
+                    if self.accept() {
+                        self.draw_line(None)?;
+                        println!("");
+                        return Ok(Some(self.input.trim().to_owned()));
+                    }
+                }
+                BACKSPACE | DELETE => {
+                    self.input.pop();
+                }
+                TAB => self.complete(),
+                ESCAPE => {
+                    //Throw away escape sequences like the arrow keys. They look like ESC [ params final
+                    if let Some(Ok(b'[')) = bytes.next() {
+                        while let Some(Ok(b)) = bytes.next() {
+                            if b >= 0x40 && b <= 0x7E {
+                                break;
+                            }
+                        }
+                    }
+                }
+                b if send::is_key_separator(b as char) => {
+                    if !self.input.is_empty() && !self.input.ends_with(' ') {
+                        self.input.push(' ');
+                    }
+                }
+                b if b.is_ascii_graphic() => self.input.push((b as char).to_ascii_lowercase()),
+                _ => {},
+            }
+            self.draw()?;
+        }
+        println!("");
+        return Ok(None);
+    }
+
+    fn current_word(&self) -> &str {
+        return self.input.rsplit(' ').next().unwrap_or("");
+    }
+
+    fn words(&self) -> Vec<&str> {
+        return self.input.split(' ')
+            .filter(|x| !x.is_empty())
+            .collect();
+    }
+
+    fn complete(&mut self) {
+        let word = self.current_word().to_owned();
+        if word.is_empty() {
+            return;
+        }
+        let range = self.dictionary.prefix_range(&word);
+        if range.start == range.end {
+            self.message = Some(format!("no word starts with {}", word));
+            return;
+        }
+
+        let first = self.dictionary.get(range.start);
+        let last = self.dictionary.get(range.end - 1);
+        //Sorted, so the first and last have the least in common
+        let completion = common_prefix(first, last).to_owned();
+        let base = self.input.len() - word.len();
+        self.input.truncate(base);
+        self.input.push_str(&completion);
+        if range.end - range.start == 1 {
+            self.input.push(' ');
+        }
+    }
+
+    //Checks the input before we let it go. Points out the first mistake if there is one
+    fn accept(&mut self) -> bool {
+        let words = self.words();
+        if words.is_empty() {
+            return false;
+        }
+        if let Some(word) = words.iter().find(|x| self.dictionary.find(x).is_none()) {
+            self.message = Some(format!("{} is not a word{}", word, self.suggest(word)));
+            return false;
+        }
+        if let Err(err) = (self.decode)(&words.join(" ")) {
+            self.message = Some(err);
+            return false;
+        }
+        return true;
+    }
+
+    //Find some words that share as much as possible with a misspelled one
+    fn suggest(&self, word: &str) -> String {
+        for len in (1..word.len()).rev() {
+            let range = self.dictionary.prefix_range(&word[..len]);
+            if range.start != range.end {
+                return format!(", did you mean {}?", self.show_range(range.start, range.end));
+            }
+        }
+        return "".to_owned();
+    }
+
+    fn show_range(&self, start: u32, end: u32) -> String {
+        let mut shown = (start..end)
+            .take(SHOWN_COMPLETIONS)
+            .map(|x| self.dictionary.get(x))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if (end - start) as usize > SHOWN_COMPLETIONS {
+            shown.push_str(" ...");
+        }
+        return shown;
+    }
+
+    fn status(&self) -> String {
+        if let Some(ref message) = self.message {
+            return Red.paint(message.clone()).to_string();
+        }
+
+        //While a word is being typed show what it might become
+        let word = self.current_word();
+        if !word.is_empty() && self.dictionary.find(word).is_none() {
+            let range = self.dictionary.prefix_range(word);
+            if range.start == range.end {
+                return Red.paint("unknown word").to_string();
+            }
+            return Fixed(244).paint(self.show_range(range.start, range.end)).to_string();
+        }
+
+        let words = self.words();
+        if words.is_empty() {
+            return "".to_owned();
+        }
+        return match (self.decode)(&words.join(" ")) {
+            Ok(desc) => format!("{} {}", Blue.paint("=>"), Yellow.paint(desc)),
+            Err(err) => Red.paint(err).to_string(),
+        };
+    }
+
+    fn draw(&self) -> io::Result<()> {
+        let status = self.status();
+        return self.draw_line(Some(status));
+    }
+
+    fn draw_line(&self, status: Option<String>) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        //Clear the line, write the input and put the status after it while keeping the cursor
+        //at the end of the input
+        write!(out, "\r\x1b[K{} {}", Green.paint("Key:"), self.input)?;
+        if let Some(status) = status {
+            write!(out, "\x1b7  {}\x1b8", status)?;
+        }
+        return out.flush();
+    }
+}
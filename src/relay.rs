@@ -0,0 +1,314 @@
+use std;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::Readn;
+use super::errors::*;
+use super::Timeouts;
+
+pub const DEFAULT_RELAY_PORT: u16 = 2223;
+
+//Sent by the relay to both sides when they have been paired
+const PAIRED: u8 = 1;
+//Sent by the relay when someone is already waiting with the same role and code
+const TAKEN: u8 = 2;
+//Codes are short, so anything longer is someone talking the wrong protocol
+const MAX_CODE_LEN: u32 = 256;
+
+//Servers wait at the relay for clients to join them. Peers are only ever paired with the other
+//role, so two servers can't end up talking to each other.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Role {
+    Listen,
+    Join,
+}
+
+impl Role {
+    fn other(&self) -> Role {
+        return match *self {
+            Role::Listen => Role::Join,
+            Role::Join => Role::Listen,
+        };
+    }
+}
+
+//The first thing a peer sends to the relay. Peers sending the same code get connected
+struct Hello {
+    role: Role,
+    code: String,
+}
+
+impl Hello {
+    fn read<T: Read>(stream: &mut T) -> Result<Hello> {
+        let role = match stream.read_u8()? {
+            0 => Role::Listen,
+            1 => Role::Join,
+            _ => bail!(ErrorKind::InvalidRelayCode),
+        };
+        let code_len = stream.read_u32::<BigEndian>()?;
+        if code_len > MAX_CODE_LEN {
+            bail!(ErrorKind::InvalidRelayCode);
+        }
+        let mut code_buff = Vec::with_capacity(code_len as usize);
+        let code_read = stream.readn(&mut code_buff, code_len as usize)?;
+        if code_read != code_len as usize {
+            bail!(ErrorKind::IncompleteRead(code_read, code_len as usize));
+        }
+        let code = String::from_utf8(code_buff)
+            .chain_err(|| ErrorKind::InvalidRelayCode)?;
+        return Ok(Hello {
+            role: role,
+            code: code,
+        });
+    }
+
+    fn write<T: Write>(&self, stream: &mut T) -> Result<()> {
+        stream.write_u8(match self.role {
+            Role::Listen => 0,
+            Role::Join => 1,
+        })?;
+        stream.write_u32::<BigEndian>(self.code.len() as u32)?;
+        stream.write_all(self.code.as_bytes())?;
+        return Ok(());
+    }
+}
+
+//Connects out to a relay and waits there until a peer with the same code shows up. The stream
+//we get back is connected straight through to the peer.
+pub fn connect(relay: SocketAddrV4, role: Role, code: &str, timeouts: &Timeouts) -> Result<TcpStream> {
+    let mut stream = timeouts.connect(relay)?;
+    let hello = Hello {
+        role: role,
+        code: code.to_owned(),
+    };
+    hello.write(&mut stream)?;
+
+    //Listeners wait for as long as it takes for someone to show up. Keepalive tells us if the
+    //relay goes away in the meantime
+    if role == Role::Listen {
+        stream.set_read_timeout(None)?;
+    }
+    let mut paired = [0u8; 1];
+    stream.read_exact(&mut paired)
+        .chain_err(|| ErrorKind::RelayPairing(code.to_owned()))?;
+    match paired[0] {
+        PAIRED => {},
+        TAKEN => bail!(ErrorKind::RelayCodeTaken(code.to_owned())),
+        _ => bail!(ErrorKind::RelayPairing(code.to_owned())),
+    }
+    timeouts.configure(&stream)?;
+    return Ok(stream);
+}
+
+//Pairs up peers that can't reach each other by code and splices their connections together.
+pub struct Relay {
+    addr: SocketAddrV4,
+    waiting: Mutex<HashMap<(Role, String), (TcpStream, SocketAddr)>>,
+    timeouts: Timeouts,
+}
+
+impl Relay {
+    pub fn new(addr: SocketAddrV4) -> Self {
+        return Relay {
+            addr: addr,
+            waiting: Mutex::new(HashMap::new()),
+            timeouts: Timeouts::default(),
+        };
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn run(relay: Arc<Relay>) -> Result<()> {
+        let listener = TcpListener::bind(relay.addr)
+            .chain_err(|| ErrorKind::Bind(*relay.addr.ip(), relay.addr.port()))?;
+        return Relay::accept(relay, listener);
+    }
+
+    fn accept(relay: Arc<Relay>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, remote_addr) = listener.accept()
+                .chain_err(|| ErrorKind::ServerConnection)?;
+            let relay = relay.clone();
+            //Reading the hello can block for as long as the peer wants, so don't hold up the
+            //listener for it
+            std::thread::spawn(move || {
+                if let Err(err) = relay.pair(stream, remote_addr) {
+                    super::print_err(Error::with_chain(err, ErrorKind::RelayConnection(remote_addr)));
+                }
+            });
+        }
+    }
+
+    fn pair(&self, mut stream: TcpStream, remote_addr: SocketAddr) -> Result<()> {
+        //Waiting peers are never read from, so the idle timeout only matters once they are spliced
+        self.timeouts.configure(&stream)?;
+        let hello = Hello::read(&mut stream)?;
+
+        let mut waiting = self.waiting.lock().unwrap();
+        if let Some((mut other, other_addr)) = waiting.remove(&(hello.role.other(), hello.code.clone())) {
+            //The waiting side might have given up in the meantime. Then we take its place
+            if is_open(&other) && other.write_all(&[PAIRED]).is_ok() {
+                drop(waiting);
+                stream.write_all(&[PAIRED])?;
+                info!("Paired {} and {} on {}", other_addr, remote_addr, hello.code);
+                return splice(other, stream);
+            }
+        }
+        //Whoever came first keeps the code, unless it has hung up since
+        let key = (hello.role, hello.code);
+        if let Some(&(ref other, other_addr)) = waiting.get(&key) {
+            if is_open(other) {
+                drop(waiting);
+                info!("Refused {}, {} is already waiting on {}", remote_addr, other_addr, key.1);
+                stream.write_all(&[TAKEN])?;
+                return Ok(());
+            }
+        }
+        waiting.insert(key, (stream, remote_addr));
+        return Ok(());
+    }
+}
+
+//Waiting peers send nothing until they are paired, so anything but a read that would block means
+//they hung up. Writing isn't enough to tell, since that still works on a half closed connection.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = match stream.peek(&mut [0u8; 1]) {
+        Ok(read) => read > 0,
+        Err(err) => err.kind() == std::io::ErrorKind::WouldBlock,
+    };
+    return stream.set_nonblocking(false).is_ok() && open;
+}
+
+fn copy_half(mut from: TcpStream, mut to: TcpStream) {
+    let _ = std::io::copy(&mut from, &mut to);
+    //Let the other side know nothing more is coming
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+fn splice(a: TcpStream, b: TcpStream) -> Result<()> {
+    let a_read = a.try_clone()?;
+    let b_read = b.try_clone()?;
+    let forward = std::thread::spawn(move || copy_half(a_read, b));
+    copy_half(b_read, a);
+    let _ = forward.join();
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTLE_MILLIS: u64 = 200;
+
+    fn start_relay() -> SocketAddrV4 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let relay = Arc::new(Relay::new(addr));
+        std::thread::spawn(move || Relay::accept(relay, listener));
+        return addr;
+    }
+
+    fn settle() {
+        std::thread::sleep(std::time::Duration::from_millis(SETTLE_MILLIS));
+    }
+
+    //Listens at the relay and echoes back whatever the peer sends
+    fn echo_server(relay: SocketAddrV4, code: &'static str) -> std::thread::JoinHandle<()> {
+        return std::thread::spawn(move || {
+            let mut stream = connect(relay, Role::Listen, code, &Timeouts::default()).unwrap();
+            let mut buff = [0u8; 5];
+            stream.read_exact(&mut buff).unwrap();
+            stream.write_all(&buff).unwrap();
+        });
+    }
+
+    fn check_echo(stream: &mut TcpStream) {
+        stream.write_all(b"hello").unwrap();
+        let mut buff = [0u8; 5];
+        stream.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff, b"hello");
+    }
+
+    #[test]
+    fn pairs_server_and_client() {
+        let relay = start_relay();
+        let server = echo_server(relay, "pair");
+        settle();
+
+        let mut client = connect(relay, Role::Join, "pair", &Timeouts::default()).unwrap();
+        check_echo(&mut client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn only_pairs_matching_codes() {
+        let relay = start_relay();
+        let server = echo_server(relay, "right");
+        settle();
+
+        let mut timeouts = Timeouts::default();
+        timeouts.idle = std::time::Duration::from_millis(SETTLE_MILLIS);
+        assert!(connect(relay, Role::Join, "wrong", &timeouts).is_err());
+
+        let mut client = connect(relay, Role::Join, "right", &Timeouts::default()).unwrap();
+        check_echo(&mut client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn refuses_a_second_waiter_on_the_same_code() {
+        let relay = start_relay();
+        let server = echo_server(relay, "taken");
+        settle();
+
+        match connect(relay, Role::Listen, "taken", &Timeouts::default()) {
+            Err(Error(ErrorKind::RelayCodeTaken(_), _)) => {},
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Second waiter was accepted"),
+        }
+
+        //The first one is still there to be paired with
+        let mut client = connect(relay, Role::Join, "taken", &Timeouts::default()).unwrap();
+        check_echo(&mut client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn skips_waiters_that_hung_up() {
+        let relay = start_relay();
+
+        //Says hello and then gives up, leaving only the reading half open
+        let mut stale = TcpStream::connect(relay).unwrap();
+        let hello = Hello {
+            role: Role::Listen,
+            code: "stale".to_owned(),
+        };
+        hello.write(&mut stale).unwrap();
+        stale.shutdown(Shutdown::Write).unwrap();
+        settle();
+
+        let client = std::thread::spawn(move || {
+            let mut client = connect(relay, Role::Join, "stale", &Timeouts::default()).unwrap();
+            check_echo(&mut client);
+        });
+        settle();
+
+        let server = echo_server(relay, "stale");
+        client.join().unwrap();
+        server.join().unwrap();
+
+        let mut rest = Vec::new();
+        stale.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,25 @@
+extern crate libc;
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//How many times we have been asked to stop. The handler can't do much else safely
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn handle(_: libc::c_int) {
+    RECEIVED.fetch_add(1, Ordering::SeqCst);
+}
+
+//Count SIGINT and SIGTERM instead of dying on them
+pub fn install() -> io::Result<()> {
+    for &signal in &[libc::SIGINT, libc::SIGTERM] {
+        if unsafe{ libc::signal(signal, handle as *const () as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    return Ok(());
+}
+
+pub fn received() -> usize {
+    return RECEIVED.load(Ordering::SeqCst);
+}